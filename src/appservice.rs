@@ -0,0 +1,799 @@
+//! Application service mode.
+//!
+//! Rather than logging in with a password and syncing, the bot can register
+//! with its homeserver as an [application service]. The homeserver then pushes
+//! transactions to the bot over HTTP, and the bot may act as any user in its
+//! namespace.
+//!
+//! [application service]: https://spec.matrix.org/unstable/application-service-api/
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::{
+            appservice::event::push_events,
+            client::{
+                error::ErrorKind,
+                r0::{
+                    account::register::{self, LoginType},
+                    uiaa::UiaaResponse,
+                },
+            },
+            error::{FromHttpResponseError, ServerError},
+            IncomingRequest,
+        },
+        events::{
+            room::member::{MemberEventContent, MembershipState},
+            SyncStateEvent,
+        },
+        UserId,
+    },
+    Client, ClientConfig, HttpError, RequestConfig, Session,
+};
+use regex::Regex;
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{event, Level};
+use url::Url;
+
+use crate::config::{AccountConfig, AppServiceConfig};
+use crate::errors::*;
+use crate::invites::Invites;
+use crate::joins::JoinQueue;
+use crate::shutdown::Shutdown;
+use crate::BingoBot;
+
+/// The number of transaction IDs to remember for deduplication.
+const SEEN_TRANSACTIONS: usize = 128;
+
+impl AppServiceConfig {
+    fn user_namespace(&self) -> String {
+        self.user_namespace
+            .clone()
+            .unwrap_or_else(|| format!("{}_.*", regex::escape(&self.sender_localpart)))
+    }
+
+    /// Builds the registration the homeserver needs in order to talk to the
+    /// bot.
+    pub fn registration(&self) -> Registration {
+        Registration {
+            id: self.id.clone(),
+            url: self.url.clone(),
+            as_token: self.as_token.clone(),
+            hs_token: self.hs_token.clone(),
+            sender_localpart: self.sender_localpart.clone(),
+            namespaces: Namespaces {
+                users: vec![Namespace {
+                    exclusive: true,
+                    regex: format!(
+                        "@{}:{}",
+                        self.user_namespace(),
+                        regex::escape(&self.server_name)
+                    ),
+                }],
+                aliases: vec![],
+                rooms: vec![],
+            },
+            rate_limited: false,
+        }
+    }
+}
+
+/// An application service registration, as loaded by the homeserver.
+#[derive(Debug, Serialize)]
+pub struct Registration {
+    pub id: String,
+    pub url: String,
+    pub as_token: String,
+    pub hs_token: String,
+    pub sender_localpart: String,
+    pub namespaces: Namespaces,
+    pub rate_limited: bool,
+}
+
+impl Registration {
+    pub fn to_yaml(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Namespaces {
+    pub users: Vec<Namespace>,
+    pub aliases: Vec<Namespace>,
+    pub rooms: Vec<Namespace>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Namespace {
+    pub exclusive: bool,
+    pub regex: String,
+}
+
+/// Receives the requests the homeserver pushes to the application service.
+#[async_trait]
+pub trait TransactionHandler: Send + Sync + 'static {
+    /// Processes the JSON body of the transaction `txn_id`.
+    async fn handle_transaction(&self, txn_id: &str, body: Vec<u8>) -> Result<()>;
+
+    /// Returns whether `user_id` exists, creating it if necessary.
+    async fn query_user(&self, _user_id: &str) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+pub struct AppService {
+    bot: BingoBot,
+    config: AppServiceConfig,
+    homeserver: Url,
+    store_path: PathBuf,
+    user_namespace: Regex,
+}
+
+impl AppService {
+    pub fn new(
+        account: &AccountConfig,
+        store_path: &Path,
+        handler_config: Option<HashMap<String, String>>,
+    ) -> Result<Self> {
+        let config = account.appservice.clone().ok_or_else(|| {
+            Error::BotError(format!(
+                "account {} has no appservice configuration",
+                account.name()
+            ))
+        })?;
+        let homeserver = Url::parse(&account.homeserver)?;
+        let user_namespace = Regex::new(&format!("^{}$", config.user_namespace()))
+            .map_err(|e| Error::BotError(format!("invalid user namespace: {}", e)))?;
+
+        let sp = store_path.to_string_lossy().to_string();
+        event!(Level::DEBUG, "store path: {}", &sp);
+        let client = Client::new_with_config(homeserver.clone(), Self::client_config(&sp))?;
+
+        Ok(Self {
+            bot: BingoBot::from_client(client, account, handler_config)?,
+            config,
+            homeserver,
+            store_path: store_path.to_path_buf(),
+            user_namespace,
+        })
+    }
+
+    /// Stops serving when `shutdown` is triggered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.bot.shutdown = shutdown;
+        self
+    }
+
+    fn client_config(store_path: &str) -> ClientConfig {
+        ClientConfig::new()
+            .store_path(store_path)
+            .appservice_mode()
+            .request_config(RequestConfig::new().assert_identity())
+    }
+
+    fn user_id(&self, localpart: &str) -> Result<UserId> {
+        Ok(UserId::try_from(format!(
+            "@{}:{}",
+            localpart, self.config.server_name
+        ))?)
+    }
+
+    async fn restore_login(&self, client: &Client, localpart: &str) -> Result<()> {
+        client
+            .restore_login(Session {
+                access_token: self.config.as_token.clone(),
+                user_id: self.user_id(localpart)?,
+                device_id: self.config.id.as_str().into(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Returns a client that acts as the namespaced virtual user `localpart`,
+    /// registering the user with the homeserver if it doesn't exist yet.
+    pub async fn virtual_user(&self, localpart: &str) -> Result<Client> {
+        if !self.user_namespace.is_match(localpart) {
+            return Err(Error::BotError(format!(
+                "{} is outside of the application service's namespace",
+                localpart
+            )));
+        }
+
+        let sp = self.store_path.join("virtual").join(localpart);
+        let client = Client::new_with_config(
+            self.homeserver.clone(),
+            Self::client_config(&sp.to_string_lossy()),
+        )?;
+        self.restore_login(&client, localpart).await?;
+
+        let mut request = register::Request::new();
+        request.username = Some(localpart);
+        request.login_type = Some(&LoginType::ApplicationService);
+        match client.register(request).await {
+            Ok(_) => event!(Level::INFO, "registered virtual user {}", localpart),
+            Err(HttpError::UiaaError(FromHttpResponseError::Http(ServerError::Known(
+                UiaaResponse::MatrixError(e),
+            )))) if e.kind == ErrorKind::UserInUse => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(client)
+    }
+
+    /// Starts serving the application service API. This only returns if the
+    /// server fails, or once shutdown has finished.
+    pub async fn run(mut self) -> Result<()> {
+        let client = self.bot.client.clone();
+        self.restore_login(&client, &self.config.sender_localpart)
+            .await?;
+        event!(
+            Level::INFO,
+            "running as application service {} for {}",
+            self.config.id,
+            client.user_id().await.unwrap()
+        );
+
+        self.bot.apply_identity().await?;
+        self.bot.follow_upgrades().await;
+        self.bot.spaces.join_all(&self.bot.joins).await;
+        self.bot.apply_room_display_names().await;
+        self.bot.register_event_handlers().await;
+        self.bot.start_handlers().await;
+        let invites = self.bot.invites.clone();
+        let joins = self.bot.joins.clone();
+        client
+            .register_event_handler(move |ev, client, room| {
+                Self::on_room_member(ev, client, room, invites.clone(), joins.clone())
+            })
+            .await;
+
+        let addr: SocketAddr = self
+            .config
+            .listen
+            .parse()
+            .map_err(|e| Error::BotError(format!("invalid listen address: {}", e)))?;
+        let hs_token = self.config.hs_token.clone();
+        let joins = self.bot.joins.clone();
+        let this = Arc::new(self);
+        let (addr, server) = bind(addr, hs_token, this.clone())?;
+        let retries = tokio::spawn(joins.run());
+        event!(Level::INFO, "listening for transactions on {}", addr);
+        let result = tokio::select! {
+            r = server => r,
+            _ = this.bot.shutdown.wait() => {
+                event!(Level::INFO, "shutting down");
+                Ok(())
+            }
+        };
+        retries.abort();
+        this.bot.tasks.stop();
+
+        if this.bot.shutdown.is_triggered() {
+            this.bot.finish().await;
+        } else {
+            this.bot.stop_handlers().await;
+        }
+        result
+    }
+
+    /// Invites arrive as ordinary timeline events in application service
+    /// mode, rather than as stripped state.
+    async fn on_room_member(
+        room_member: SyncStateEvent<MemberEventContent>,
+        client: Client,
+        room: Room,
+        invites: Arc<Invites>,
+        joins: Arc<JoinQueue>,
+    ) {
+        if room_member.content.membership != MembershipState::Invite
+            || room_member.state_key != client.user_id().await.unwrap().as_str()
+        {
+            return;
+        }
+
+        if !invites.review(room.room_id(), &room_member.sender).await {
+            return;
+        }
+
+        event!(Level::INFO, "accepting invite to room {}", room.room_id());
+        if let Err(e) = client.join_room_by_id(room.room_id()).await {
+            joins.add(room.room_id(), &e.into()).await;
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionHandler for AppService {
+    async fn handle_transaction(&self, txn_id: &str, body: Vec<u8>) -> Result<()> {
+        let request = hyper::Request::builder()
+            .method(Method::PUT)
+            .uri(format!("/_matrix/app/v1/transactions/{}", txn_id))
+            .body(body)
+            .map_err(|e| Error::BotError(e.to_string()))?;
+        let incoming = push_events::v1::IncomingRequest::try_from_http_request(request)
+            .map_err(|e| Error::BotError(format!("invalid transaction: {}", e)))?;
+
+        event!(
+            Level::DEBUG,
+            "received transaction {} with {} events",
+            txn_id,
+            incoming.events.len()
+        );
+        self.bot.client.receive_transaction(incoming).await?;
+        Ok(())
+    }
+
+    async fn query_user(&self, user_id: &str) -> bool {
+        let localpart = match UserId::try_from(user_id) {
+            Ok(u) if u.server_name().as_str() == self.config.server_name => {
+                u.localpart().to_string()
+            }
+            _ => return false,
+        };
+
+        match self.virtual_user(&localpart).await {
+            Ok(_) => true,
+            Err(e) => {
+                event!(Level::DEBUG, "not creating user {}: {}", user_id, e);
+                false
+            }
+        }
+    }
+}
+
+struct ApiServer<H> {
+    hs_token: String,
+    handler: Arc<H>,
+    seen: Mutex<VecDeque<String>>,
+    in_flight: Mutex<HashMap<String, watch::Receiver<Option<bool>>>>,
+}
+
+/// How a request should treat the transaction it carries.
+enum Claim<'a, H> {
+    /// The transaction was already processed.
+    Done,
+    /// Another request is processing the transaction; the receiver yields
+    /// whether it succeeded.
+    Pending(watch::Receiver<Option<bool>>),
+    /// This request processes the transaction.
+    Mine(Processing<'a, H>),
+}
+
+/// Marks a transaction as in flight until dropped, then records it as seen
+/// if it succeeded and tells any duplicates waiting on it.
+struct Processing<'a, H> {
+    server: &'a ApiServer<H>,
+    txn_id: String,
+    result: watch::Sender<Option<bool>>,
+    ok: bool,
+}
+
+impl<H> Drop for Processing<'_, H> {
+    fn drop(&mut self) {
+        let mut seen = self.server.seen.lock().unwrap();
+        if self.ok {
+            if seen.len() == SEEN_TRANSACTIONS {
+                seen.pop_front();
+            }
+            seen.push_back(self.txn_id.clone());
+        }
+        self.server.in_flight.lock().unwrap().remove(&self.txn_id);
+        let _ = self.result.send(Some(self.ok));
+    }
+}
+
+/// Binds the application service HTTP API to `addr`, returning the bound
+/// address and a future that runs the server.
+pub fn bind<H: TransactionHandler>(
+    addr: SocketAddr,
+    hs_token: String,
+    handler: Arc<H>,
+) -> Result<(SocketAddr, impl std::future::Future<Output = Result<()>>)> {
+    let server = Arc::new(ApiServer {
+        hs_token,
+        handler,
+        seen: Mutex::new(VecDeque::with_capacity(SEEN_TRANSACTIONS)),
+        in_flight: Mutex::new(HashMap::new()),
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let server = server.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                let server = server.clone();
+                async move { Ok::<_, hyper::Error>(server.route(req).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    let addr = server.local_addr();
+    Ok((addr, async move { Ok(server.await?) }))
+}
+
+impl<H: TransactionHandler> ApiServer<H> {
+    async fn route(&self, req: Request<Body>) -> Response<Body> {
+        if let Some(resp) = self.authorize(&req) {
+            return resp;
+        }
+
+        let path = req.uri().path().to_string();
+        let path = path.strip_prefix("/_matrix/app/v1").unwrap_or(&path);
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+        match (req.method(), segments.as_slice()) {
+            (&Method::PUT, ["transactions", txn_id]) => {
+                let txn_id = txn_id.to_string();
+                self.transaction(&txn_id, req).await
+            }
+            (&Method::GET, ["users", user_id]) => {
+                let user_id = percent_decode(user_id);
+                if self.handler.query_user(&user_id).await {
+                    json_response(StatusCode::OK, "{}")
+                } else {
+                    error_response(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such user")
+                }
+            }
+            (&Method::GET, ["rooms", _]) => {
+                error_response(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such room")
+            }
+            _ => error_response(
+                StatusCode::NOT_FOUND,
+                "M_UNRECOGNIZED",
+                "unrecognized request",
+            ),
+        }
+    }
+
+    fn authorize(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let from_query = req.uri().query().and_then(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .find(|(k, _)| k == "access_token")
+                .map(|(_, v)| v.into_owned())
+        });
+        let from_header = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(String::from);
+
+        match from_query.or(from_header) {
+            None => Some(error_response(
+                StatusCode::UNAUTHORIZED,
+                "M_UNAUTHORIZED",
+                "missing access token",
+            )),
+            Some(token) if token != self.hs_token => {
+                event!(Level::WARN, "rejected request with an invalid hs_token");
+                Some(error_response(
+                    StatusCode::FORBIDDEN,
+                    "M_FORBIDDEN",
+                    "invalid access token",
+                ))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Marks `txn_id` as seen, returning false if it already was. This
+    /// happens before the transaction is processed, so that a retry arriving
+    /// while it's still being processed is recognised as a duplicate.
+    fn claim(&self, txn_id: &str) -> Claim<'_, H> {
+        let seen = self.seen.lock().unwrap();
+        if seen.iter().any(|t| t == txn_id) {
+            return Claim::Done;
+        }
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(rx) = in_flight.get(txn_id) {
+            return Claim::Pending(rx.clone());
+        }
+        let (tx, rx) = watch::channel(None);
+        in_flight.insert(txn_id.to_string(), rx);
+        Claim::Mine(Processing {
+            server: self,
+            txn_id: txn_id.to_string(),
+            result: tx,
+            ok: false,
+        })
+    }
+
+    async fn transaction(&self, txn_id: &str, req: Request<Body>) -> Response<Body> {
+        let mut processing = match self.claim(txn_id) {
+            Claim::Done => {
+                event!(Level::DEBUG, "ignoring duplicate transaction {}", txn_id);
+                return json_response(StatusCode::OK, "{}");
+            }
+            Claim::Pending(mut rx) => {
+                event!(Level::DEBUG, "waiting for duplicate transaction {}", txn_id);
+                let ok = rx.changed().await.is_ok() && *rx.borrow() == Some(true);
+                return if ok {
+                    json_response(StatusCode::OK, "{}")
+                } else {
+                    error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "M_UNKNOWN",
+                        "transaction failed",
+                    )
+                };
+            }
+            Claim::Mine(processing) => processing,
+        };
+
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(b) => b.to_vec(),
+            Err(e) => {
+                event!(Level::WARN, "error reading transaction {}: {}", txn_id, e);
+                return error_response(StatusCode::BAD_REQUEST, "M_BAD_JSON", "unreadable body");
+            }
+        };
+
+        if let Err(e) = self.handler.handle_transaction(txn_id, body).await {
+            event!(
+                Level::ERROR,
+                "error processing transaction {}: {}",
+                txn_id,
+                e
+            );
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "M_UNKNOWN",
+                &e.to_string(),
+            );
+        }
+
+        processing.ok = true;
+        json_response(StatusCode::OK, "{}")
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    url::form_urlencoded::parse(format!("x={}", s).as_bytes())
+        .map(|(_, v)| v.into_owned())
+        .next()
+        .unwrap_or_default()
+}
+
+fn json_response(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, errcode: &str, error: &str) -> Response<Body> {
+    let body = serde_json::json!({ "errcode": errcode, "error": error });
+    json_response(status, &body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const HS_TOKEN: &str = "hs_secret";
+
+    /// Stands in for the bot, recording the transactions it receives.
+    #[derive(Default)]
+    struct Recorder {
+        transactions: Mutex<Vec<(String, serde_json::Value)>>,
+        /// If set, each transaction waits for a permit before it is handled.
+        gate: Option<tokio::sync::Notify>,
+    }
+
+    #[async_trait]
+    impl TransactionHandler for Recorder {
+        async fn handle_transaction(&self, txn_id: &str, body: Vec<u8>) -> Result<()> {
+            if let Some(gate) = &self.gate {
+                gate.notified().await;
+            }
+            let body = serde_json::from_slice(&body).map_err(|e| Error::BotError(e.to_string()))?;
+            self.transactions
+                .lock()
+                .unwrap()
+                .push((txn_id.to_string(), body));
+            Ok(())
+        }
+
+        async fn query_user(&self, user_id: &str) -> bool {
+            user_id == "@bingo_alt:example.org"
+        }
+    }
+
+    async fn start() -> (String, Arc<Recorder>) {
+        start_with(Recorder::default()).await
+    }
+
+    async fn start_with(recorder: Recorder) -> (String, Arc<Recorder>) {
+        let recorder = Arc::new(recorder);
+        let (addr, server) = bind(
+            "127.0.0.1:0".parse().unwrap(),
+            HS_TOKEN.into(),
+            recorder.clone(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+        (format!("http://{}/_matrix/app/v1", addr), recorder)
+    }
+
+    fn transaction() -> serde_json::Value {
+        serde_json::json!({
+            "events": [{
+                "type": "m.room.message",
+                "event_id": "$1:example.org",
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 1,
+                "content": { "msgtype": "m.text", "body": "!help" }
+            }]
+        })
+    }
+
+    #[tokio::test]
+    async fn pushes_transactions_once() {
+        let (base, recorder) = start().await;
+        let client = reqwest::Client::new();
+
+        for _ in 0..2 {
+            let resp = client
+                .put(format!("{}/transactions/1?access_token={}", base, HS_TOKEN))
+                .json(&transaction())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let transactions = recorder.transactions.lock().unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].0, "1");
+        assert_eq!(transactions[0].1, transaction());
+    }
+
+    #[tokio::test]
+    async fn pushes_concurrent_duplicates_once() {
+        let (base, recorder) = start().await;
+        let client = reqwest::Client::new();
+        let url = format!("{}/transactions/1?access_token={}", base, HS_TOKEN);
+
+        let (a, b) = tokio::join!(
+            client.put(&url).json(&transaction()).send(),
+            client.put(&url).json(&transaction()).send(),
+        );
+        assert_eq!(a.unwrap().status(), StatusCode::OK);
+        assert_eq!(b.unwrap().status(), StatusCode::OK);
+
+        assert_eq!(recorder.transactions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_failed_transactions() {
+        let (base, recorder) = start().await;
+        let client = reqwest::Client::new();
+        let url = format!("{}/transactions/1?access_token={}", base, HS_TOKEN);
+
+        let resp = client.put(&url).body("not json").send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let resp = client.put(&url).json(&transaction()).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(recorder.transactions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn waits_for_transactions_in_flight() {
+        let (base, recorder) = start_with(Recorder {
+            gate: Some(tokio::sync::Notify::new()),
+            ..Default::default()
+        })
+        .await;
+        let gate = recorder.gate.as_ref().unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("{}/transactions/1?access_token={}", base, HS_TOKEN);
+
+        let first = tokio::spawn(client.put(&url).body("not json").send());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut retry = tokio::spawn(client.put(&url).json(&transaction()).send());
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut retry)
+            .await
+            .is_err());
+
+        gate.notify_one();
+        let status = |resp: reqwest::Result<reqwest::Response>| resp.unwrap().status();
+        assert_eq!(
+            status(first.await.unwrap()),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            status(retry.await.unwrap()),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(recorder.transactions.lock().unwrap().is_empty());
+
+        let retry = tokio::spawn(client.put(&url).json(&transaction()).send());
+        gate.notify_one();
+        assert_eq!(status(retry.await.unwrap()), StatusCode::OK);
+        assert_eq!(recorder.transactions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_tokens() {
+        let (base, recorder) = start().await;
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .put(format!("{}/transactions/1", base))
+            .json(&transaction())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = client
+            .put(format!("{}/transactions/1?access_token=nope", base))
+            .json(&transaction())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        assert!(recorder.transactions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queries_users() {
+        let (base, _) = start().await;
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .get(format!("{}/users/%40bingo_alt%3Aexample.org", base))
+            .bearer_auth(HS_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = client
+            .get(format!("{}/users/%40mallory%3Aexample.org", base))
+            .bearer_auth(HS_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn generates_registration() {
+        let config = AppServiceConfig {
+            id: "bingo".into(),
+            url: "http://localhost:9000".into(),
+            listen: "127.0.0.1:9000".into(),
+            as_token: "as_secret".into(),
+            hs_token: HS_TOKEN.into(),
+            sender_localpart: "bingo".into(),
+            server_name: "example.org".into(),
+            user_namespace: None,
+        };
+
+        let yaml = config.registration().to_yaml().unwrap();
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(parsed["sender_localpart"].as_str(), Some("bingo"));
+        assert_eq!(
+            parsed["namespaces"]["users"][0]["regex"].as_str(),
+            Some(r"@bingo_.*:example\.org")
+        );
+    }
+}
@@ -1,9 +1,18 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::path::{Path, PathBuf};
 
+use bingo_bot::config::{AccountConfig, DEFAULT_EMPTY_ROOM_GRACE};
+use bingo_bot::shutdown::Shutdown;
 use bingo_bot::BingoBot;
-use config::Config;
+use config::{Config, ConfigError};
 use directories::ProjectDirs;
+use serde::de::DeserializeOwned;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{event, Instrument, Level};
+
+/// The longest an account waits before being restarted after a failure.
+const MAX_RESTART_DELAY: u64 = 300;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -14,9 +23,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .merge(config::Environment::with_prefix("BINGO"))
         .unwrap();
 
-    let homeserver = settings.get::<String>("homeserver")?;
-    let username = settings.get::<String>("username")?;
-    let password = settings.get::<String>("password")?;
+    let accounts = accounts(&settings)?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--generate-registration") {
+        return generate_registration(&accounts, args.get(1));
+    }
 
     let mut level_filter = "bingo_bot=info";
     if settings.get::<bool>("debug").unwrap_or(false) {
@@ -48,8 +60,192 @@ async fn main() -> Result<(), Box<dyn Error>> {
         );
     }
 
-    let mut bot = BingoBot::new(&homeserver, data_dir, Some(conf))?;
-    bot.login_and_sync(&username, &password).await?;
+    let shutdown = Shutdown::new();
+    shutdown.on_signals();
+
+    let mut tasks = vec![];
+    for account in accounts {
+        let span = tracing::info_span!("account", name = account.name());
+        tasks.push(tokio::spawn(
+            supervise(
+                account,
+                data_dir.to_path_buf(),
+                conf.clone(),
+                shutdown.clone(),
+            )
+            .instrument(span),
+        ));
+    }
+    for task in tasks {
+        task.await?;
+    }
 
     Ok(())
 }
+
+/// Reads the `[[accounts]]` list from the configuration. A configuration
+/// without one describes a single account at the top level, whose store is
+/// the data directory itself.
+fn accounts(settings: &Config) -> Result<Vec<AccountConfig>, Box<dyn Error>> {
+    if settings.get_array("accounts").is_ok() {
+        return Ok(settings.get::<Vec<AccountConfig>>("accounts")?);
+    }
+
+    Ok(vec![AccountConfig {
+        name: None,
+        homeserver: settings.get("homeserver")?,
+        username: optional(settings, "username")?,
+        password: optional(settings, "password")?,
+        store: Some(String::new()),
+        display_name: optional(settings, "display_name")?,
+        avatar: optional(settings, "avatar")?,
+        rooms: optional(settings, "rooms")?.unwrap_or_default(),
+        spaces: optional(settings, "spaces")?.unwrap_or_default(),
+        empty_room_grace: optional(settings, "empty_room_grace")?
+            .unwrap_or(DEFAULT_EMPTY_ROOM_GRACE),
+        admins: optional(settings, "admins")?.unwrap_or_default(),
+        invites: optional(settings, "invites")?.unwrap_or_default(),
+        ignore: optional(settings, "ignore")?.unwrap_or_default(),
+        circuit_breaker: optional(settings, "circuit_breaker")?.unwrap_or_default(),
+        cooldowns: optional(settings, "cooldowns")?.unwrap_or_default(),
+        groups: optional(settings, "groups")?.unwrap_or_default(),
+        permissions: optional(settings, "permissions")?.unwrap_or_default(),
+        timeouts: optional(settings, "timeouts")?.unwrap_or_default(),
+        shutdown: optional(settings, "shutdown")?.unwrap_or_default(),
+        timezone: optional(settings, "timezone")?,
+        handlers: None,
+        appservice: optional(settings, "appservice")?,
+    }])
+}
+
+fn optional<T: DeserializeOwned>(settings: &Config, key: &str) -> Result<Option<T>, ConfigError> {
+    match settings.get(key) {
+        Ok(v) => Ok(Some(v)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs an account until shutdown, restarting it whenever it fails so that
+/// one account's problems don't take down the others.
+async fn supervise(
+    account: AccountConfig,
+    data_dir: PathBuf,
+    conf: HashMap<String, String>,
+    shutdown: Shutdown,
+) {
+    let mut delay = 1;
+    loop {
+        let started = Instant::now();
+        let run = tokio::spawn(
+            run_account(
+                account.clone(),
+                data_dir.clone(),
+                conf.clone(),
+                shutdown.clone(),
+            )
+            .in_current_span(),
+        );
+
+        match run.await {
+            Ok(Ok(())) if shutdown.is_triggered() => event!(Level::INFO, "account shut down"),
+            Ok(Ok(())) => event!(Level::WARN, "account stopped"),
+            Ok(Err(e)) => event!(Level::ERROR, "account failed: {}", e),
+            Err(e) => event!(Level::ERROR, "account crashed: {}", e),
+        }
+        if shutdown.is_triggered() {
+            return;
+        }
+
+        if started.elapsed() > Duration::from_secs(MAX_RESTART_DELAY) {
+            delay = 1;
+        }
+        event!(Level::INFO, "restarting account in {}s", delay);
+        tokio::select! {
+            _ = sleep(Duration::from_secs(delay)) => {}
+            _ = shutdown.wait() => return,
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+async fn run_account(
+    account: AccountConfig,
+    data_dir: PathBuf,
+    conf: HashMap<String, String>,
+    shutdown: Shutdown,
+) -> bingo_bot::Result<()> {
+    let store_path = data_dir.join(account.store());
+    std::fs::create_dir_all(&store_path)?;
+
+    if account.appservice.is_some() {
+        return run_appservice(&account, &store_path, conf, shutdown).await;
+    }
+
+    let (username, password) = match (&account.username, &account.password) {
+        (Some(u), Some(p)) => (u, p),
+        _ => {
+            return Err(bingo_bot::Error::BotError(
+                "a username and password are required".into(),
+            ))
+        }
+    };
+
+    let mut bot = BingoBot::builder(&account, &store_path)
+        .handler_config(conf)
+        .shutdown(shutdown)
+        .build()?;
+    bot.login_and_sync(username, password).await
+}
+
+#[cfg(feature = "appservice")]
+async fn run_appservice(
+    account: &AccountConfig,
+    store_path: &Path,
+    conf: HashMap<String, String>,
+    shutdown: Shutdown,
+) -> bingo_bot::Result<()> {
+    let appservice = bingo_bot::appservice::AppService::new(account, store_path, Some(conf))?
+        .with_shutdown(shutdown);
+    appservice.run().await
+}
+
+#[cfg(not(feature = "appservice"))]
+async fn run_appservice(
+    _: &AccountConfig,
+    _: &Path,
+    _: HashMap<String, String>,
+    _: Shutdown,
+) -> bingo_bot::Result<()> {
+    Err(bingo_bot::Error::BotError(
+        "bingo-bot was built without the \"appservice\" feature".into(),
+    ))
+}
+
+#[cfg(feature = "appservice")]
+fn generate_registration(
+    accounts: &[AccountConfig],
+    path: Option<&String>,
+) -> Result<(), Box<dyn Error>> {
+    let as_config = match accounts.iter().find_map(|a| a.appservice.as_ref()) {
+        Some(c) => c,
+        None => {
+            eprintln!("no account is configured as an application service");
+            std::process::exit(1);
+        }
+    };
+    let yaml = as_config.registration().to_yaml()?;
+
+    match path {
+        Some(p) => std::fs::write(p, yaml)?,
+        None => print!("{}", yaml),
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "appservice"))]
+fn generate_registration(_: &[AccountConfig], _: Option<&String>) -> Result<(), Box<dyn Error>> {
+    eprintln!("bingo-bot was built without the \"appservice\" feature");
+    std::process::exit(1);
+}
@@ -0,0 +1,147 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use matrix_sdk::ruma::RoomId;
+use tokio::time::{Duration, Instant};
+use tracing::{event, Level};
+
+use crate::config::CircuitBreakerConfig;
+use crate::handlers::{Handler, Message};
+use crate::middleware::{Middleware, Verdict};
+
+#[derive(Debug, Default)]
+struct RoomState {
+    /// When the bot's recent responses in the room were sent.
+    sent: VecDeque<Instant>,
+    muted_until: Option<Instant>,
+}
+
+/// Mutes the bot in a room once it has responded there too often in a short
+/// time, which usually means it's caught in a loop with another bot.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    max_responses: usize,
+    window: Duration,
+    mute: Duration,
+    rooms: Mutex<HashMap<RoomId, RoomState>>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            max_responses: config.max_responses,
+            window: Duration::from_secs(config.window),
+            mute: Duration::from_secs(config.mute),
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn is_muted(&self, room_id: &RoomId) -> bool {
+        let rooms = self.rooms.lock().unwrap();
+        matches!(
+            rooms.get(room_id).and_then(|r| r.muted_until),
+            Some(until) if until > Instant::now()
+        )
+    }
+
+    /// Records a response sent in the room, tripping the breaker if it's
+    /// one too many.
+    pub(crate) fn record(&self, room_id: &RoomId) {
+        let now = Instant::now();
+        let mut rooms = self.rooms.lock().unwrap();
+        let state = rooms.entry(room_id.clone()).or_default();
+
+        state.sent.push_back(now);
+        while matches!(state.sent.front(), Some(t) if now.duration_since(*t) > self.window) {
+            state.sent.pop_front();
+        }
+
+        if state.sent.len() > self.max_responses {
+            event!(
+                Level::WARN,
+                "muting in {} for {}s after {} responses in {}s",
+                room_id,
+                self.mute.as_secs(),
+                state.sent.len(),
+                self.window.as_secs()
+            );
+            state.sent.clear();
+            state.muted_until = Some(now + self.mute);
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for CircuitBreaker {
+    fn name(&self) -> &str {
+        "circuit breaker"
+    }
+
+    async fn allow(&self, message: &Message, _handler: &dyn Handler) -> Verdict {
+        if self.is_muted(&message.room_id) {
+            event!(Level::DEBUG, "muted in {}", message.room_id);
+            return Verdict::Deny(None);
+        }
+        Verdict::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn breaker(max_responses: usize, window: u64, mute: u64) -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig {
+            max_responses,
+            window,
+            mute,
+        })
+    }
+
+    fn room(id: &str) -> RoomId {
+        RoomId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn trips_after_too_many_responses() {
+        let breaker = breaker(3, 60, 60);
+        let here = room("!here:example.org");
+        let there = room("!there:example.org");
+
+        for _ in 0..3 {
+            breaker.record(&here);
+            assert!(!breaker.is_muted(&here));
+        }
+        breaker.record(&there);
+        assert!(!breaker.is_muted(&there));
+
+        breaker.record(&here);
+        assert!(breaker.is_muted(&here));
+        assert!(!breaker.is_muted(&there));
+    }
+
+    #[test]
+    fn forgets_responses_outside_the_window() {
+        let breaker = breaker(1, 0, 60);
+        let here = room("!here:example.org");
+
+        for _ in 0..3 {
+            breaker.record(&here);
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert!(!breaker.is_muted(&here));
+    }
+
+    #[test]
+    fn unmutes_once_the_mute_is_over() {
+        let breaker = breaker(1, 60, 0);
+        let here = room("!here:example.org");
+
+        breaker.record(&here);
+        breaker.record(&here);
+        assert!(!breaker.is_muted(&here));
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use regex::Regex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+/// The display name used when an account doesn't configure one.
+pub const DEFAULT_DISPLAY_NAME: &str = "Bingo";
+
+/// How long the bot stays in a room after everyone else leaves, in seconds,
+/// when an account doesn't configure it.
+pub const DEFAULT_EMPTY_ROOM_GRACE: u64 = 300;
+
+/// A single Matrix account for the bot to run as, from an `[[accounts]]`
+/// entry in the bot configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountConfig {
+    /// A name for the account, used in logs. Defaults to the username.
+    pub name: Option<String>,
+    pub homeserver: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Subdirectory of the data directory for the account's state store.
+    /// Defaults to the account name.
+    pub store: Option<String>,
+    pub display_name: Option<String>,
+    /// An image to upload as the bot's avatar on startup.
+    pub avatar: Option<PathBuf>,
+    /// Per-room settings, keyed by room ID.
+    #[serde(default)]
+    pub rooms: HashMap<String, RoomConfig>,
+    /// Settings for every room in a space, keyed by space ID. The bot joins
+    /// each of these spaces and all of their rooms.
+    #[serde(default)]
+    pub spaces: HashMap<String, RoomConfig>,
+    /// How long to stay in a room after everyone else leaves, in seconds.
+    #[serde(default = "default_empty_room_grace")]
+    pub empty_room_grace: u64,
+    /// Users allowed to administer the bot, such as approving invites.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Which room invites the bot accepts.
+    #[serde(default)]
+    pub invites: InviteConfig,
+    /// Users whose messages the bot never responds to.
+    #[serde(default)]
+    pub ignore: IgnoreConfig,
+    /// Mutes the bot in a room where it's responding too often.
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Cooldowns for handlers, by name, replacing the handlers' own.
+    #[serde(default)]
+    pub cooldowns: HashMap<String, CooldownConfig>,
+    /// Named groups of user IDs, for use in permissions.
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    /// Who may use each handler, by name, replacing the handlers' own
+    /// requirements. Some commands have entries of their own, such as
+    /// `quote.delete`.
+    #[serde(default)]
+    pub permissions: HashMap<String, Permission>,
+    /// How long handlers may take to respond.
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    /// What to do when the bot is asked to stop.
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    /// The timezone for scheduled jobs, such as "Europe/London". Defaults
+    /// to UTC.
+    pub timezone: Option<String>,
+    /// The handlers to enable for the account, by name. Defaults to all of
+    /// them.
+    pub handlers: Option<Vec<String>>,
+    /// Run the account as an application service instead of logging in.
+    pub appservice: Option<AppServiceConfig>,
+}
+
+impl AccountConfig {
+    pub fn name(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.username.as_deref())
+            .or_else(|| self.appservice.as_ref().map(|a| a.id.as_str()))
+            .unwrap_or("default")
+    }
+
+    pub fn store(&self) -> &str {
+        self.store.as_deref().unwrap_or_else(|| self.name())
+    }
+
+    pub fn handler_enabled(&self, name: &str) -> bool {
+        match &self.handlers {
+            Some(h) => h.iter().any(|n| n == name),
+            None => true,
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(DEFAULT_DISPLAY_NAME)
+    }
+
+    /// Returns every display name the bot may go by.
+    pub fn display_names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.display_name()).chain(
+            self.rooms
+                .values()
+                .chain(self.spaces.values())
+                .filter_map(|r| r.display_name.as_deref()),
+        )
+    }
+}
+
+/// Settings that apply to a single room, or to every room in a space.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RoomConfig {
+    /// Overrides the bot's display name in this room.
+    pub display_name: Option<String>,
+    /// The handlers allowed to respond in this room, by name. Defaults to
+    /// all of the account's handlers.
+    pub handlers: Option<Vec<String>>,
+    /// Greets members when they join the room.
+    pub welcome: Option<WelcomeConfig>,
+}
+
+/// How the bot greets new members of a room.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WelcomeConfig {
+    /// The greeting, in Markdown. `{name}` is replaced with the new
+    /// members' display names and `{room}` with the room's name.
+    pub message: String,
+    /// Links listed after the greeting.
+    #[serde(default)]
+    pub links: Vec<WelcomeLink>,
+    /// The fewest seconds between greetings. Members who join in the
+    /// meantime are greeted together once it has passed.
+    #[serde(default = "default_welcome_cooldown")]
+    pub cooldown: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WelcomeLink {
+    pub text: String,
+    pub url: String,
+}
+
+fn default_welcome_cooldown() -> u64 {
+    60
+}
+
+/// Rules for which room invites the bot accepts. With no allowlists, invites
+/// from anyone are accepted.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct InviteConfig {
+    /// Users whose invites are accepted.
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+    /// Homeservers whose users' invites are accepted.
+    #[serde(default)]
+    pub allowed_servers: Vec<String>,
+    /// The most rooms the bot may be joined to at once.
+    pub max_rooms: Option<usize>,
+    /// Ask the account's admins by direct message about invites that aren't
+    /// allowlisted, instead of rejecting them.
+    #[serde(default)]
+    pub require_approval: bool,
+}
+
+/// Users the bot doesn't respond to, to keep it out of conversations with
+/// other bots.
+#[derive(Debug, Clone, Deserialize)]
+pub struct IgnoreConfig {
+    /// User IDs to ignore.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Regexes matched against the whole user ID of each sender.
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub patterns: Vec<String>,
+    /// Ignore anyone who has sent an `m.notice`, as bots do.
+    #[serde(default = "default_true")]
+    pub notice_senders: bool,
+}
+
+impl Default for IgnoreConfig {
+    fn default() -> Self {
+        Self {
+            users: Vec::new(),
+            patterns: Vec::new(),
+            notice_senders: true,
+        }
+    }
+}
+
+/// Limits on how often the bot responds in a room. Once it has sent more
+/// than `max_responses` within `window` seconds, it stays quiet in that room
+/// for `mute` seconds.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_max_responses")]
+    pub max_responses: usize,
+    #[serde(default = "default_breaker_window")]
+    pub window: u64,
+    #[serde(default = "default_breaker_mute")]
+    pub mute: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            max_responses: default_max_responses(),
+            window: default_breaker_window(),
+            mute: default_breaker_mute(),
+        }
+    }
+}
+
+/// How long a handler waits before responding again, in seconds. Each
+/// window starts when the handler responds.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CooldownConfig {
+    /// Before responding to the same user again, in any room.
+    pub per_user: Option<u64>,
+    /// Before responding in the same room again.
+    pub per_room: Option<u64>,
+    /// Before responding anywhere again.
+    pub global: Option<u64>,
+    /// Tell users once when they hit a cooldown, instead of staying silent.
+    #[serde(default)]
+    pub notify: bool,
+}
+
+/// Who may use a handler. The account's admins may use every handler.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Anyone,
+    /// Room members with at least this power level.
+    PowerLevel(i64),
+    /// Only the account's admins.
+    Admin,
+    /// Members of the named group in `groups`.
+    Group(String),
+}
+
+/// How long handlers may take to respond, in seconds.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimeoutConfig {
+    #[serde(default = "default_timeout")]
+    pub default: u64,
+    /// Timeouts for particular handlers, by name.
+    #[serde(default)]
+    pub handlers: HashMap<String, u64>,
+    /// Tell users when a handler times out, instead of staying silent.
+    #[serde(default)]
+    pub report: bool,
+}
+
+impl TimeoutConfig {
+    /// Returns the named handler's timeout.
+    pub fn get(&self, handler: &str) -> u64 {
+        self.handlers.get(handler).copied().unwrap_or(self.default)
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            default: default_timeout(),
+            handlers: HashMap::new(),
+            report: false,
+        }
+    }
+}
+
+/// How the bot shuts down on SIGINT or SIGTERM.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShutdownConfig {
+    /// The longest to wait for responses in progress, in seconds.
+    #[serde(default = "default_shutdown_timeout")]
+    pub timeout: u64,
+    /// Set the bot's presence to offline before stopping.
+    #[serde(default)]
+    pub set_offline: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout: default_shutdown_timeout(),
+            set_offline: false,
+        }
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    20
+}
+
+/// Reads a list of regexes, failing on any that don't compile.
+fn deserialize_patterns<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    for p in &patterns {
+        Regex::new(p).map_err(|e| D::Error::custom(format!("invalid pattern {}: {}", p, e)))?;
+    }
+    Ok(patterns)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_responses() -> usize {
+    10
+}
+
+fn default_breaker_window() -> u64 {
+    60
+}
+
+fn default_breaker_mute() -> u64 {
+    300
+}
+
+fn default_empty_room_grace() -> u64 {
+    DEFAULT_EMPTY_ROOM_GRACE
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:9000".into()
+}
+
+/// The `appservice` section of an account.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppServiceConfig {
+    /// Unique identifier for this application service registration.
+    pub id: String,
+    /// The URL at which the homeserver can reach the bot.
+    pub url: String,
+    /// The local address to listen on for requests from the homeserver.
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// Token the bot uses to authenticate to the homeserver.
+    pub as_token: String,
+    /// Token the homeserver uses to authenticate to the bot.
+    pub hs_token: String,
+    /// Localpart of the bot's own user.
+    pub sender_localpart: String,
+    /// Server name of the homeserver, used to build user IDs.
+    pub server_name: String,
+    /// Regex matching the localparts of virtual users the bot may act as.
+    /// Defaults to `<sender_localpart>_.*`.
+    pub user_namespace: Option<String>,
+}
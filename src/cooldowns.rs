@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
+use tokio::time::{Duration, Instant};
+use tracing::{event, Level};
+
+use crate::config::CooldownConfig;
+use crate::handlers::{Handler, Message};
+use crate::middleware::{Middleware, Verdict};
+
+/// What a cooldown applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    User(UserId),
+    Room(RoomId),
+    Global,
+}
+
+#[derive(Debug)]
+struct Window {
+    until: Instant,
+    /// Whether someone has been told about this window already.
+    notified: bool,
+    /// The message the window was reserved for, until the handler's response
+    /// to it is sent.
+    reserved_for: Option<EventId>,
+}
+
+/// The outcome of checking a handler's cooldowns.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Cooldown {
+    Ready,
+    /// The handler is cooling down. `notify` is set the first time this is
+    /// seen, if the handler's cooldown asks for users to be told.
+    Waiting {
+        remaining: Duration,
+        notify: bool,
+    },
+}
+
+/// Tracks when each handler may next respond, per user, per room and
+/// overall. A handler's windows are reserved as soon as it's allowed to
+/// handle a message, so that messages arriving while it works are held back
+/// too, and restarted once its response is sent. If nothing it said is sent,
+/// the reservation is released.
+#[derive(Debug)]
+pub(crate) struct Cooldowns {
+    /// Cooldowns configured for the account, replacing the handlers' own.
+    overrides: HashMap<String, CooldownConfig>,
+    windows: Mutex<HashMap<(String, Scope), Window>>,
+}
+
+impl Cooldowns {
+    pub(crate) fn new(overrides: HashMap<String, CooldownConfig>) -> Self {
+        Self {
+            overrides,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn config(&self, handler: &dyn Handler) -> CooldownConfig {
+        self.overrides
+            .get(handler.name())
+            .cloned()
+            .unwrap_or_else(|| handler.cooldown())
+    }
+
+    /// Checks whether `handler` may handle `message`, reserving its windows
+    /// if it may.
+    fn reserve_at(&self, handler: &dyn Handler, message: &Message, now: Instant) -> Cooldown {
+        let config = self.config(handler);
+        let mut windows = self.windows.lock().unwrap();
+
+        let longest = scopes(&config, &message.room_id, &message.sender)
+            .map(|(scope, _)| (handler.name().to_string(), scope))
+            .filter(|key| matches!(windows.get(key), Some(w) if w.until > now))
+            .max_by_key(|key| windows[key].until);
+        if let Some(window) = longest.and_then(|key| windows.get_mut(&key)) {
+            let notify = config.notify && !window.notified;
+            window.notified = true;
+            return Cooldown::Waiting {
+                remaining: window.until - now,
+                notify,
+            };
+        }
+
+        windows.retain(|_, w| w.until > now);
+        for (scope, secs) in scopes(&config, &message.room_id, &message.sender) {
+            windows.insert(
+                (handler.name().to_string(), scope),
+                Window {
+                    until: now + Duration::from_secs(secs),
+                    notified: false,
+                    reserved_for: Some(message.event_id.clone()),
+                },
+            );
+        }
+        Cooldown::Ready
+    }
+
+    /// Restarts `handler`'s windows once its response to `message` is sent.
+    fn start_at(&self, handler: &dyn Handler, message: &Message, now: Instant) {
+        let config = self.config(handler);
+        let mut windows = self.windows.lock().unwrap();
+
+        for (scope, secs) in scopes(&config, &message.room_id, &message.sender) {
+            windows.insert(
+                (handler.name().to_string(), scope),
+                Window {
+                    until: now + Duration::from_secs(secs),
+                    notified: false,
+                    reserved_for: None,
+                },
+            );
+        }
+    }
+
+    /// Drops the windows reserved for `message`, since nothing `handler`
+    /// said in answer to it was sent.
+    fn unreserve(&self, handler: &dyn Handler, message: &Message) {
+        let config = self.config(handler);
+        let mut windows = self.windows.lock().unwrap();
+
+        for (scope, _) in scopes(&config, &message.room_id, &message.sender) {
+            let key = (handler.name().to_string(), scope);
+            if matches!(windows.get(&key), Some(w) if w.reserved_for.as_ref() == Some(&message.event_id))
+            {
+                windows.remove(&key);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Cooldowns {
+    fn name(&self) -> &str {
+        "cooldowns"
+    }
+
+    async fn allow(&self, message: &Message, handler: &dyn Handler) -> Verdict {
+        match self.reserve_at(handler, message, Instant::now()) {
+            Cooldown::Ready => Verdict::Allow,
+            Cooldown::Waiting { remaining, notify } => {
+                event!(
+                    Level::DEBUG,
+                    "{} is cooling down for {}s",
+                    handler.name(),
+                    remaining.as_secs()
+                );
+                Verdict::Deny(notify.then(|| {
+                    format!(
+                        "Slow down, {}! Try again in {}s.",
+                        message.sender_name,
+                        remaining.as_secs().max(1)
+                    )
+                }))
+            }
+        }
+    }
+
+    async fn release(&self, message: &Message, handler: &dyn Handler) {
+        self.unreserve(handler, message);
+    }
+
+    async fn after(
+        &self,
+        message: &Message,
+        handler: &dyn Handler,
+        response: AnyMessageEventContent,
+    ) -> Option<AnyMessageEventContent> {
+        self.start_at(handler, message, Instant::now());
+        Some(response)
+    }
+}
+
+/// Returns the scopes `config` has a cooldown for, with their lengths.
+fn scopes(
+    config: &CooldownConfig,
+    room_id: &RoomId,
+    user: &UserId,
+) -> impl Iterator<Item = (Scope, u64)> {
+    vec![
+        (Scope::User(user.clone()), config.per_user),
+        (Scope::Room(room_id.clone()), config.per_room),
+        (Scope::Global, config.global),
+    ]
+    .into_iter()
+    .filter_map(|(scope, secs)| secs.map(|s| (scope, s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::handlers::{Match, MessageKind};
+
+    #[derive(Debug)]
+    struct Cooling(CooldownConfig);
+
+    #[async_trait]
+    impl Handler for Cooling {
+        fn name(&self) -> &str {
+            "cooling"
+        }
+
+        fn cmd(&self) -> &str {
+            ""
+        }
+
+        fn description(&self) -> &str {
+            ""
+        }
+
+        fn cooldown(&self) -> CooldownConfig {
+            self.0.clone()
+        }
+
+        async fn handle(&self, _m: &Match) -> Option<AnyMessageEventContent> {
+            None
+        }
+    }
+
+    fn message(room: &str, sender: &str, event: &str) -> Message {
+        Message {
+            kind: MessageKind::Text,
+            room_id: RoomId::try_from(room).unwrap(),
+            event_id: EventId::try_from(event).unwrap(),
+            sender: UserId::try_from(sender).unwrap(),
+            sender_name: "someone".into(),
+            body: "!cool".into(),
+            formatted_body: None,
+            mentions: None,
+            is_edit: false,
+        }
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    fn is_ready(cooldown: Cooldown) -> bool {
+        cooldown == Cooldown::Ready
+    }
+
+    #[test]
+    fn cools_down_per_user() {
+        let handler = Cooling(CooldownConfig {
+            per_user: Some(30),
+            ..CooldownConfig::default()
+        });
+        let cooldowns = Cooldowns::new(HashMap::new());
+        let now = Instant::now();
+
+        let first = message("!here:example.org", "@alice:example.org", "$1:example.org");
+        assert!(is_ready(cooldowns.reserve_at(&handler, &first, now)));
+        cooldowns.start_at(&handler, &first, now);
+
+        let elsewhere = message("!there:example.org", "@alice:example.org", "$2:example.org");
+        assert!(!is_ready(cooldowns.reserve_at(&handler, &elsewhere, now)));
+        let bob = message("!here:example.org", "@bob:example.org", "$3:example.org");
+        assert!(is_ready(cooldowns.reserve_at(&handler, &bob, now)));
+        let later = message("!here:example.org", "@alice:example.org", "$4:example.org");
+        assert!(is_ready(cooldowns.reserve_at(
+            &handler,
+            &later,
+            now + secs(30)
+        )));
+    }
+
+    #[test]
+    fn cools_down_per_room() {
+        let handler = Cooling(CooldownConfig {
+            per_room: Some(10),
+            ..CooldownConfig::default()
+        });
+        let cooldowns = Cooldowns::new(HashMap::new());
+        let now = Instant::now();
+
+        let first = message("!here:example.org", "@alice:example.org", "$1:example.org");
+        assert!(is_ready(cooldowns.reserve_at(&handler, &first, now)));
+        cooldowns.start_at(&handler, &first, now);
+
+        let bob = message("!here:example.org", "@bob:example.org", "$2:example.org");
+        assert!(!is_ready(cooldowns.reserve_at(
+            &handler,
+            &bob,
+            now + secs(9)
+        )));
+        let elsewhere = message("!there:example.org", "@alice:example.org", "$3:example.org");
+        assert!(is_ready(cooldowns.reserve_at(&handler, &elsewhere, now)));
+        assert!(is_ready(cooldowns.reserve_at(
+            &handler,
+            &bob,
+            now + secs(10)
+        )));
+    }
+
+    #[test]
+    fn cools_down_globally_for_the_longest_window() {
+        let handler = Cooling(CooldownConfig {
+            per_user: Some(30),
+            global: Some(2),
+            ..CooldownConfig::default()
+        });
+        let cooldowns = Cooldowns::new(HashMap::new());
+        let now = Instant::now();
+
+        let first = message("!here:example.org", "@alice:example.org", "$1:example.org");
+        assert!(is_ready(cooldowns.reserve_at(&handler, &first, now)));
+        cooldowns.start_at(&handler, &first, now);
+
+        let bob = message("!there:example.org", "@bob:example.org", "$2:example.org");
+        assert_eq!(
+            cooldowns.reserve_at(&handler, &bob, now + secs(1)),
+            Cooldown::Waiting {
+                remaining: secs(1),
+                notify: false
+            }
+        );
+        let alice = message("!there:example.org", "@alice:example.org", "$3:example.org");
+        assert_eq!(
+            cooldowns.reserve_at(&handler, &alice, now + secs(1)),
+            Cooldown::Waiting {
+                remaining: secs(29),
+                notify: false
+            }
+        );
+        assert!(is_ready(cooldowns.reserve_at(
+            &handler,
+            &bob,
+            now + secs(2)
+        )));
+    }
+
+    #[test]
+    fn notifies_once_per_window() {
+        let handler = Cooling(CooldownConfig {
+            per_user: Some(30),
+            notify: true,
+            ..CooldownConfig::default()
+        });
+        let cooldowns = Cooldowns::new(HashMap::new());
+        let now = Instant::now();
+
+        let first = message("!here:example.org", "@alice:example.org", "$1:example.org");
+        cooldowns.reserve_at(&handler, &first, now);
+        cooldowns.start_at(&handler, &first, now);
+        let again = message("!here:example.org", "@alice:example.org", "$2:example.org");
+        let notified = |at| match cooldowns.reserve_at(&handler, &again, at) {
+            Cooldown::Waiting { notify, .. } => notify,
+            Cooldown::Ready => panic!("not cooling down"),
+        };
+        assert!(notified(now));
+        assert!(!notified(now + secs(1)));
+
+        // a new window gets a new notification
+        cooldowns.start_at(&handler, &first, now + secs(30));
+        assert!(notified(now + secs(31)));
+    }
+
+    #[tokio::test]
+    async fn reserves_windows_before_responding() {
+        let handler = Cooling(CooldownConfig {
+            global: Some(2),
+            ..CooldownConfig::default()
+        });
+        let cooldowns = Cooldowns::new(HashMap::new());
+        let first = message("!here:example.org", "@alice:example.org", "$1:example.org");
+        let second = message("!there:example.org", "@bob:example.org", "$2:example.org");
+
+        // both arrive while the first is still being handled
+        assert_eq!(cooldowns.allow(&first, &handler).await, Verdict::Allow);
+        assert_eq!(
+            cooldowns.allow(&second, &handler).await,
+            Verdict::Deny(None)
+        );
+
+        // the first had nothing to say, so the second may try
+        Middleware::release(&cooldowns, &first, &handler).await;
+        assert_eq!(cooldowns.allow(&second, &handler).await, Verdict::Allow);
+
+        // releasing a message that didn't reserve the window changes nothing
+        Middleware::release(&cooldowns, &first, &handler).await;
+        let third = message("!here:example.org", "@carol:example.org", "$3:example.org");
+        assert_eq!(cooldowns.allow(&third, &handler).await, Verdict::Deny(None));
+
+        // once the second's response is sent, its window stands
+        let response = crate::handlers::new_message("ok".into()).unwrap();
+        cooldowns.after(&second, &handler, response).await;
+        Middleware::release(&cooldowns, &second, &handler).await;
+        assert_eq!(cooldowns.allow(&third, &handler).await, Verdict::Deny(None));
+    }
+
+    #[test]
+    fn the_account_overrides_handlers() {
+        let handler = Cooling(CooldownConfig {
+            per_user: Some(30),
+            ..CooldownConfig::default()
+        });
+        let overrides = vec![("cooling".to_string(), CooldownConfig::default())];
+        let cooldowns = Cooldowns::new(overrides.into_iter().collect());
+        let now = Instant::now();
+
+        let first = message("!here:example.org", "@alice:example.org", "$1:example.org");
+        cooldowns.reserve_at(&handler, &first, now);
+        cooldowns.start_at(&handler, &first, now);
+        let again = message("!here:example.org", "@alice:example.org", "$2:example.org");
+        assert!(is_ready(cooldowns.reserve_at(&handler, &again, now)));
+    }
+}
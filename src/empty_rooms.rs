@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::api::client::r0::membership::forget_room;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use tokio::time::{sleep, Duration};
+use tracing::{event, Level};
+
+use crate::errors::*;
+
+/// Leaves rooms once the bot has been the only member for a grace period.
+#[derive(Debug)]
+pub(crate) struct EmptyRooms {
+    client: Client,
+    grace: Duration,
+    scheduled: Mutex<HashSet<RoomId>>,
+}
+
+impl EmptyRooms {
+    pub(crate) fn new(client: Client, grace: Duration) -> Self {
+        Self {
+            client,
+            grace,
+            scheduled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Starts the grace period for `room` if the bot is its only member.
+    pub(crate) async fn check(self: &Arc<Self>, room: &Joined) {
+        if !is_empty(room).await {
+            return;
+        }
+
+        let room_id = room.room_id().clone();
+        if !self.scheduled.lock().unwrap().insert(room_id.clone()) {
+            return;
+        }
+        event!(
+            Level::INFO,
+            "everyone else has left {}, leaving in {}s",
+            room_id,
+            self.grace.as_secs()
+        );
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            sleep(this.grace).await;
+            this.scheduled.lock().unwrap().remove(&room_id);
+            if let Err(e) = this.leave_if_empty(&room_id).await {
+                event!(Level::WARN, "failed to leave empty room {}: {}", room_id, e);
+            }
+        });
+    }
+
+    async fn leave_if_empty(&self, room_id: &RoomId) -> Result<()> {
+        let room = match self.client.get_joined_room(room_id) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        if !is_empty(&room).await {
+            event!(
+                Level::DEBUG,
+                "{} is no longer empty, staying in it",
+                room_id
+            );
+            return Ok(());
+        }
+
+        room.leave().await?;
+        self.client
+            .send(forget_room::Request::new(room_id), None)
+            .await?;
+        event!(Level::INFO, "left and forgot empty room {}", room_id);
+
+        Ok(())
+    }
+}
+
+async fn is_empty(room: &Joined) -> bool {
+    match room.active_members().await {
+        Ok(members) => members.len() <= 1,
+        Err(_) => false,
+    }
+}
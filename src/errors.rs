@@ -5,16 +5,30 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     BotError(String),
+    #[cfg(feature = "appservice")]
+    Hyper(hyper::Error),
+    Identifier(matrix_sdk::ruma::identifiers::Error),
+    Io(std::io::Error),
+    Json(serde_json::Error),
     MatrixError(matrix_sdk::Error),
     Url(url::ParseError),
+    #[cfg(feature = "appservice")]
+    Yaml(serde_yaml::Error),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::BotError(e) => write!(f, "{}", e),
+            #[cfg(feature = "appservice")]
+            Self::Hyper(e) => e.fmt(f),
+            Self::Identifier(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+            Self::Json(e) => e.fmt(f),
             Self::MatrixError(e) => e.fmt(f),
             Self::Url(e) => e.fmt(f),
+            #[cfg(feature = "appservice")]
+            Self::Yaml(e) => e.fmt(f),
         }
     }
 }
@@ -36,4 +50,23 @@ macro_rules! error_from {
 }
 
 error_from!(matrix_sdk::Error, Error, MatrixError);
+error_from!(matrix_sdk::ruma::identifiers::Error, Error, Identifier);
+error_from!(std::io::Error, Error, Io);
+error_from!(serde_json::Error, Error, Json);
 error_from!(url::ParseError, Error, Url);
+#[cfg(feature = "appservice")]
+error_from!(hyper::Error, Error, Hyper);
+#[cfg(feature = "appservice")]
+error_from!(serde_yaml::Error, Error, Yaml);
+
+impl From<matrix_sdk::HttpError> for Error {
+    fn from(err: matrix_sdk::HttpError) -> Self {
+        Self::MatrixError(err.into())
+    }
+}
+
+impl From<matrix_sdk::StoreError> for Error {
+    fn from(err: matrix_sdk::StoreError) -> Self {
+        Self::MatrixError(err.into())
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use matrix_sdk::ruma::{EventId, UserId};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+use crate::errors::*;
+use crate::store;
+
+/// Custom store key holding the feedback counts.
+const FEEDBACK_KEY: &[u8] = b"bingo.feedback";
+
+/// Custom store key holding the recent votes behind the counts, so that
+/// repeated votes can be ignored and redacted ones taken back.
+const VOTES_KEY: &[u8] = b"bingo.feedback.votes";
+
+/// How many votes are kept. Older votes still count, but can no longer be
+/// taken back.
+const MAX_VOTES: usize = 1000;
+
+/// Thumbs up and down given to one handler's responses.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeedbackCount {
+    pub up: u64,
+    pub down: u64,
+}
+
+/// A 👍 or 👎 reaction to one of the bot's responses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Vote {
+    /// The reaction's event ID.
+    reaction: EventId,
+    response: EventId,
+    user: UserId,
+    handler: String,
+    up: bool,
+}
+
+/// Counts 👍 and 👎 reactions to the bot's responses, by handler. Each user
+/// counts at most once each way for each response.
+#[derive(Debug)]
+pub struct Feedback {
+    client: Client,
+    lock: Mutex<()>,
+}
+
+impl Feedback {
+    pub(crate) fn new(client: Client) -> Self {
+        Self {
+            client,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the counts so far, keyed by handler name.
+    pub async fn counts(&self) -> Result<BTreeMap<String, FeedbackCount>> {
+        store::load(&self.client, FEEDBACK_KEY).await
+    }
+
+    /// Counts `user`'s reaction to `response`, which `handler` sent.
+    pub(crate) async fn record(
+        &self,
+        handler: &str,
+        response: &EventId,
+        user: &UserId,
+        reaction: &EventId,
+        up: bool,
+    ) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut votes: VecDeque<Vote> = store::load(&self.client, VOTES_KEY).await?;
+        if votes
+            .iter()
+            .any(|v| &v.response == response && &v.user == user && v.up == up)
+        {
+            event!(
+                Level::DEBUG,
+                "{} already gave feedback on {}",
+                user,
+                response
+            );
+            return Ok(());
+        }
+
+        votes.push_back(Vote {
+            reaction: reaction.clone(),
+            response: response.clone(),
+            user: user.clone(),
+            handler: handler.into(),
+            up,
+        });
+        while votes.len() > MAX_VOTES {
+            votes.pop_front();
+        }
+        self.adjust(handler, up, true).await?;
+        store::save(&self.client, VOTES_KEY, &votes).await
+    }
+
+    /// Takes back the vote a reaction made, once the reaction is redacted.
+    pub(crate) async fn retract(&self, reaction: &EventId) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut votes: VecDeque<Vote> = store::load(&self.client, VOTES_KEY).await?;
+        let vote = match votes.iter().position(|v| &v.reaction == reaction) {
+            Some(i) => votes.remove(i).unwrap(),
+            None => return Ok(()),
+        };
+
+        self.adjust(&vote.handler, vote.up, false).await?;
+        store::save(&self.client, VOTES_KEY, &votes).await
+    }
+
+    /// Adds a vote to, or takes one from, `handler`'s counts.
+    async fn adjust(&self, handler: &str, up: bool, add: bool) -> Result<()> {
+        let mut counts = self.counts().await?;
+        let count = counts.entry(handler.to_string()).or_default();
+        let tally = if up { &mut count.up } else { &mut count.down };
+        *tally = if add {
+            *tally + 1
+        } else {
+            tally.saturating_sub(1)
+        };
+        event!(
+            Level::DEBUG,
+            "feedback for {}: {} up, {} down",
+            handler,
+            count.up,
+            count.down
+        );
+        store::save(&self.client, FEEDBACK_KEY, &counts).await
+    }
+}
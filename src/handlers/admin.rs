@@ -0,0 +1,242 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::{RoomId, UserId};
+use matrix_sdk::Client;
+use regex::Regex;
+
+use super::{Handler, Match};
+use crate::config::Permission;
+use crate::feedback::Feedback;
+use crate::ignore::IgnoreList;
+use crate::invites::{Approval, Invites};
+use crate::joins::JoinQueue;
+use crate::scheduler::{Schedule, Scheduler};
+
+/// Commands for the account's admins.
+#[derive(Debug)]
+pub struct Admin {
+    invites: Arc<Invites>,
+    joins: Arc<JoinQueue>,
+    feedback: Arc<Feedback>,
+    scheduler: Arc<Scheduler>,
+    ignore: Arc<IgnoreList>,
+    re: Regex,
+}
+
+impl Admin {
+    pub fn new(
+        _: Client,
+        invites: Arc<Invites>,
+        joins: Arc<JoinQueue>,
+        feedback: Arc<Feedback>,
+        scheduler: Arc<Scheduler>,
+        ignore: Arc<IgnoreList>,
+    ) -> Self {
+        Self {
+            invites,
+            joins,
+            feedback,
+            scheduler,
+            ignore,
+            re: Regex::new(
+                r"(?i)^(\s\*\s)?!(?P<cmd>invites|approve|reject|joins|drop|feedback|jobs|cancel|unignore)(\s+(?P<arg>\S+))?\s*$",
+            )
+            .unwrap(),
+        }
+    }
+
+    async fn list_invites(&self) -> String {
+        match self.invites.pending().await {
+            Ok(pending) if pending.is_empty() => "No invites are waiting for approval.".into(),
+            Ok(pending) => {
+                let mut lines = vec!["Invites waiting for approval:".to_string()];
+                for (room, inviter) in pending {
+                    lines.push(format!("* {} from {}", room, inviter));
+                }
+                lines.join("\n")
+            }
+            Err(e) => format!("I couldn't read the pending invites: {}", e),
+        }
+    }
+
+    async fn list_joins(&self) -> String {
+        match self.joins.pending().await {
+            Ok(pending) if pending.is_empty() => "No joins are waiting to be retried.".into(),
+            Ok(pending) => {
+                let mut lines = vec!["Joins waiting to be retried:".to_string()];
+                for (room, join) in pending {
+                    lines.push(format!(
+                        "* {} after {} attempts: {}",
+                        room, join.attempts, join.last_error
+                    ));
+                }
+                lines.join("\n")
+            }
+            Err(e) => format!("I couldn't read the pending joins: {}", e),
+        }
+    }
+
+    async fn drop_join(&self, room: &str) -> String {
+        let room_id = match RoomId::try_from(room) {
+            Ok(r) => r,
+            Err(_) => return format!("{} isn't a room ID", room),
+        };
+
+        match self.joins.drop_join(&room_id).await {
+            Ok(true) => format!("I'll stop trying to join {}.", room_id),
+            Ok(false) => format!("There's no pending join to {}.", room_id),
+            Err(e) => format!("That didn't work: {}", e),
+        }
+    }
+
+    async fn list_feedback(&self) -> String {
+        match self.feedback.counts().await {
+            Ok(counts) if counts.is_empty() => "Nobody has rated my responses yet.".into(),
+            Ok(counts) => {
+                let mut lines = vec!["Feedback on my responses:".to_string()];
+                for (handler, count) in counts {
+                    lines.push(format!("* {}: {} 👍, {} 👎", handler, count.up, count.down));
+                }
+                lines.join("\n")
+            }
+            Err(e) => format!("I couldn't read the feedback: {}", e),
+        }
+    }
+
+    async fn list_jobs(&self) -> String {
+        let tz = self.scheduler.timezone();
+        match self.scheduler.jobs().await {
+            Ok(jobs) if jobs.is_empty() => "No jobs are scheduled.".into(),
+            Ok(jobs) => {
+                let mut lines = vec!["Scheduled jobs:".to_string()];
+                for (id, job) in jobs {
+                    let next = match job.next_run() {
+                        Some(t) => t.with_timezone(&tz).format("%Y-%m-%d %H:%M %Z").to_string(),
+                        None => "never".into(),
+                    };
+                    let when = match &job.schedule {
+                        Schedule::Once(_) => format!("at {}", next),
+                        Schedule::Cron {
+                            expression,
+                            timezone,
+                        } => format!("`{}` ({}), next at {}", expression, timezone, next),
+                    };
+                    let owner = match &job.owner {
+                        Some(o) => format!(" ({})", o),
+                        None => String::new(),
+                    };
+                    lines.push(format!(
+                        "* {}: {} in {} for {}{}: {}",
+                        id, when, job.room_id, job.handler, owner, job.message
+                    ));
+                }
+                lines.join("\n")
+            }
+            Err(e) => format!("I couldn't read the scheduled jobs: {}", e),
+        }
+    }
+
+    async fn cancel_job(&self, id: &str) -> String {
+        let id = match id.trim_start_matches('#').parse() {
+            Ok(id) => id,
+            Err(_) => return format!("{} isn't a job ID", id),
+        };
+
+        match self.scheduler.cancel(id).await {
+            Ok(true) => format!("Cancelled job {}.", id),
+            Ok(false) => format!("There's no job {}.", id),
+            Err(e) => format!("That didn't work: {}", e),
+        }
+    }
+
+    async fn unignore(&self, user: &str) -> String {
+        let user_id = match UserId::try_from(user) {
+            Ok(u) => u,
+            Err(_) => return format!("{} isn't a user ID", user),
+        };
+        if self.ignore.is_configured(&user_id) {
+            return format!(
+                "{} is ignored by my configuration, so I can't stop ignoring them.",
+                user_id
+            );
+        }
+
+        match self.ignore.unignore(&user_id).await {
+            Ok(true) => format!("I'll stop ignoring {}.", user_id),
+            Ok(false) => format!("I'm not ignoring {}.", user_id),
+            Err(e) => format!("That didn't work: {}", e),
+        }
+    }
+
+    async fn decide(&self, approve: bool, room: &str) -> String {
+        let room_id = match RoomId::try_from(room) {
+            Ok(r) => r,
+            Err(_) => return format!("{} isn't a room ID", room),
+        };
+
+        let not_pending = || format!("There's no pending invite to {}.", room_id);
+        if approve {
+            return match self.invites.approve(&room_id).await {
+                Ok(Approval::Joined) => format!("Joined {}.", room_id),
+                Ok(Approval::NotPending) => not_pending(),
+                Ok(Approval::Refused(reason)) => format!(
+                    "Not joining {}: {}. The invite is still pending.",
+                    room_id, reason
+                ),
+                Err(e) => format!("That didn't work: {}", e),
+            };
+        }
+
+        match self.invites.reject(&room_id).await {
+            Ok(true) => format!("Rejected the invite to {}.", room_id),
+            Ok(false) => not_pending(),
+            Err(e) => format!("That didn't work: {}", e),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Admin {
+    fn name(&self) -> &str {
+        "admin"
+    }
+
+    fn cmd(&self) -> &str {
+        ""
+    }
+
+    fn description(&self) -> &str {
+        ""
+    }
+
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
+
+    fn permission(&self) -> Permission {
+        Permission::Admin
+    }
+
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let response = match (&m.get("cmd")?.to_lowercase()[..], m.get("arg")) {
+            ("invites", _) => self.list_invites().await,
+            ("approve", Some(room)) => self.decide(true, room).await,
+            ("reject", Some(room)) => self.decide(false, room).await,
+            ("joins", _) => self.list_joins().await,
+            ("drop", Some(room)) => self.drop_join(room).await,
+            ("feedback", _) => self.list_feedback().await,
+            ("jobs", _) => self.list_jobs().await,
+            ("cancel", Some(id)) => self.cancel_job(id).await,
+            ("cancel", None) => "Usage: !cancel <job ID>".into(),
+            ("unignore", Some(user)) => self.unignore(user).await,
+            ("unignore", None) => "Usage: !unignore <user ID>".into(),
+            (cmd, None) => format!("Usage: !{} <room ID>", cmd),
+            _ => return None,
+        };
+
+        super::new_message(response)
+    }
+}
@@ -14,10 +14,12 @@ use sha2::{Digest, Sha256};
 use tracing::{event, Level};
 use url::Url;
 
-use super::Handler;
+use super::{Handler, Match, Reaction};
+use crate::config::CooldownConfig;
 use crate::errors::*;
 
 const GIPHY_API: &str = "https://api.giphy.com/v1/gifs/translate";
+const GIPHY_RANDOM_API: &str = "https://api.giphy.com/v1/gifs/random";
 
 fn get_url(api_key: &str, keywords: &str, sender: &str) -> Result<Url> {
     let random_id = format!("{:x}", Sha256::digest(sender.as_bytes()));
@@ -28,6 +30,15 @@ fn get_url(api_key: &str, keywords: &str, sender: &str) -> Result<Url> {
     .map_err(Error::Url)
 }
 
+fn get_random_url(api_key: &str, keywords: &str, sender: &str) -> Result<Url> {
+    let random_id = format!("{:x}", Sha256::digest(sender.as_bytes()));
+    Url::parse(&format!(
+        "{}?api_key={}&tag={}&random_id={}",
+        GIPHY_RANDOM_API, api_key, keywords, random_id
+    ))
+    .map_err(Error::Url)
+}
+
 #[derive(Debug, Deserialize)]
 struct Response {
     data: GifData,
@@ -72,57 +83,9 @@ impl Giphy {
         }
         s
     }
-}
-
-#[async_trait]
-impl Handler for Giphy {
-    fn cmd(&self) -> &str {
-        "!giphy <keywords>"
-    }
-
-    fn description(&self) -> &str {
-        "Finds a GIF relevant to your interests"
-    }
-
-    async fn handle(&self, sender: &str, message: &str) -> Option<AnyMessageEventContent> {
-        let api_key = match self.api_key.as_ref() {
-            Some(k) => k,
-            None => {
-                event!(
-                    Level::WARN,
-                    "Giphy handler can't run without 'giphy_api_key' in the
-        config!"
-                );
-                return None;
-            }
-        };
-
-        let captures = match self.re.captures(message) {
-            Some(c) => c,
-            None => {
-                event!(Level::DEBUG, is_match = false);
-                return None;
-            }
-        };
-
-        let keywords = match captures.name("keywords") {
-            Some(kw) => kw,
-            None => {
-                event!(Level::DEBUG, is_match = false);
-                return None;
-            }
-        };
-
-        event!(Level::DEBUG, is_match = true);
-
-        let url = match get_url(api_key, keywords.as_str(), sender) {
-            Ok(u) => u,
-            Err(e) => {
-                event!(Level::WARN, "failed to parse URL: {:?}", e);
-                return None;
-            }
-        };
 
+    /// Fetches the GIF the GIPHY API returns from `url` and uploads it.
+    async fn post_gif(&self, url: Url) -> Option<AnyMessageEventContent> {
         let resp: reqwest::Response = match reqwest::get(url).await {
             Ok(r) => r,
             Err(e) => {
@@ -198,5 +161,79 @@ impl Handler for Giphy {
     }
 }
 
+#[async_trait]
+impl Handler for Giphy {
+    fn name(&self) -> &str {
+        "giphy"
+    }
+
+    fn cmd(&self) -> &str {
+        "!giphy <keywords>"
+    }
+
+    fn description(&self) -> &str {
+        "Finds a GIF relevant to your interests (react with 🔁 for another)"
+    }
+
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
+
+    fn cooldown(&self) -> CooldownConfig {
+        // every GIF counts against the API key's quota
+        CooldownConfig {
+            per_user: Some(30),
+            global: Some(2),
+            notify: true,
+            ..CooldownConfig::default()
+        }
+    }
+
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let api_key = match self.api_key.as_ref() {
+            Some(k) => k,
+            None => {
+                event!(
+                    Level::WARN,
+                    "Giphy handler can't run without 'giphy_api_key' in the
+        config!"
+                );
+                return None;
+            }
+        };
+
+        let keywords = m.get("keywords")?;
+        let url = match get_url(api_key, keywords, &m.message.sender_name) {
+            Ok(u) => u,
+            Err(e) => {
+                event!(Level::WARN, "failed to parse URL: {:?}", e);
+                return None;
+            }
+        };
+
+        self.post_gif(url).await
+    }
+
+    async fn on_reaction(&self, reaction: &Reaction) -> Option<AnyMessageEventContent> {
+        if reaction.key != "🔁" {
+            return None;
+        }
+        let api_key = self.api_key.as_ref()?;
+        let m = self.matches(&reaction.trigger)?;
+        let keywords = m.get("keywords")?;
+
+        event!(Level::DEBUG, "re-rolling GIF for \"{}\"", keywords);
+        let url = match get_random_url(api_key, keywords, &reaction.trigger.sender_name) {
+            Ok(u) => u,
+            Err(e) => {
+                event!(Level::WARN, "failed to parse URL: {:?}", e);
+                return None;
+            }
+        };
+
+        self.post_gif(url).await
+    }
+}
+
 unsafe impl Sync for Giphy {}
 unsafe impl Send for Giphy {}
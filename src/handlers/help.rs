@@ -2,20 +2,30 @@ use async_trait::async_trait;
 use matrix_sdk::ruma::events::AnyMessageEventContent;
 use matrix_sdk::Client;
 use regex::Regex;
-use tracing::{event, Level};
 
-use super::Handler;
+use super::{Handler, Match};
+
+const CMD: &str = "!help";
+const DESCRIPTION: &str = "Returns help information";
 
 #[derive(Debug, Clone)]
 pub struct Help {
-    client: Client,
+    commands: Vec<(String, String)>,
     re: Regex,
 }
 
 impl Help {
-    pub fn new(client: Client) -> Self {
+    /// Creates a help handler listing itself and `handlers`.
+    pub fn new(_: Client, handlers: &[Box<dyn Handler>]) -> Self {
+        let mut commands = vec![(CMD.to_string(), DESCRIPTION.to_string())];
+        for handler in handlers {
+            if !handler.cmd().is_empty() {
+                commands.push((handler.cmd().into(), handler.description().into()));
+            }
+        }
+
         Self {
-            client,
+            commands,
             re: Regex::new(r"(?i)^(\s\*\s)?!help").unwrap(),
         }
     }
@@ -23,31 +33,26 @@ impl Help {
 
 #[async_trait]
 impl Handler for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+
     fn cmd(&self) -> &str {
-        "!help"
+        CMD
     }
 
     fn description(&self) -> &str {
-        "Returns help information"
+        DESCRIPTION
     }
 
-    async fn handle(&self, _: &str, message: &str) -> Option<AnyMessageEventContent> {
-        if !self.re.is_match(message) {
-            event!(Level::DEBUG, is_match = false);
-            return None;
-        }
-
-        event!(Level::DEBUG, is_match = true);
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
 
+    async fn handle(&self, _: &Match) -> Option<AnyMessageEventContent> {
         let mut help = vec!["Here's a list of the things I respond to:".into()];
-        for handler in super::get_handlers(&self.client, None) {
-            if !handler.cmd().is_empty() {
-                help.push(format!(
-                    "* **{}** - {}",
-                    handler.cmd(),
-                    handler.description()
-                ));
-            }
+        for (cmd, description) in &self.commands {
+            help.push(format!("* **{}** - {}", cmd, description));
         }
 
         super::new_message(help.join("\n"))
@@ -1,18 +1,22 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use matrix_sdk::ruma::events::AnyMessageEventContent;
 use regex::Regex;
-use tracing::{event, Level};
 
-use super::{bot_mentioned, Handler};
+use super::{Handler, Match, MessageKind};
+use crate::mentions::MentionDetector;
 
 #[derive(Debug, Clone)]
 pub struct Howdy {
+    mentions: Arc<MentionDetector>,
     re: Regex,
 }
 
 impl Howdy {
-    pub fn new(_: matrix_sdk::Client) -> Self {
+    pub fn new(_: matrix_sdk::Client, mentions: Arc<MentionDetector>) -> Self {
         Self {
+            mentions,
             re: Regex::new(r"(?i)\b(hello|howdy|hi|oh hai)\b").unwrap(),
         }
     }
@@ -20,6 +24,10 @@ impl Howdy {
 
 #[async_trait]
 impl Handler for Howdy {
+    fn name(&self) -> &str {
+        "howdy"
+    }
+
     fn cmd(&self) -> &str {
         "hello"
     }
@@ -28,15 +36,17 @@ impl Handler for Howdy {
         "Say hello! (Responds to other greetings, too)"
     }
 
-    async fn handle(&self, sender: &str, message: &str) -> Option<AnyMessageEventContent> {
-        if !self.re.is_match(message) {
-            event!(Level::DEBUG, is_match = false);
-            return None;
-        }
+    fn accepts(&self, kind: MessageKind) -> bool {
+        matches!(kind, MessageKind::Text | MessageKind::Emote)
+    }
 
-        event!(Level::DEBUG, is_match = true);
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
 
-        if !bot_mentioned(message) {
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let message = &m.message;
+        if !self.mentions.is_mentioned(message) {
             // respond to greetings only some of the time, when not directed at us.
             if fastrand::f32() < 0.60 {
                 return None;
@@ -48,9 +58,9 @@ impl Handler for Howdy {
             "Howdy!",
             "Hello!",
             "HULLO?",
-            &format!("Hi, {}!", sender),
-            &format!("Howdy, {}!", sender),
-            &format!("Hello, {}!", sender),
+            &format!("Hi, {}!", message.sender_name),
+            &format!("Howdy, {}!", message.sender_name),
+            &format!("Hello, {}!", message.sender_name),
         ];
 
         let r = responses[fastrand::usize(..responses.len())];
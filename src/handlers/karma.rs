@@ -0,0 +1,431 @@
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::{RoomId, UserId};
+use matrix_sdk::Client;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+use super::{Handler, Match, Message, MessageKind};
+use crate::errors::*;
+use crate::middleware::Middleware;
+use crate::settings::RoomSettings;
+use crate::store;
+
+pub(crate) const NAME: &str = "karma";
+
+/// Custom store key holding the karma tallied across every room.
+const GLOBAL_KEY: &[u8] = b"bingo.karma";
+
+/// The name karma tallied in a single room is stored under.
+const ROOM_NAME: &str = "karma";
+
+/// How many reasons are kept for each thing.
+const MAX_REASONS: usize = 5;
+
+/// How many things leaderboards list.
+const TOP: usize = 10;
+
+/// The most changes a single message can make.
+const MAX_CHANGES: usize = 5;
+
+/// A thing's karma, and the most recent reasons it was given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Karma {
+    pub score: i64,
+    pub reasons: Vec<String>,
+}
+
+/// Karma by thing. Users are keyed by their user ID, and anything else by
+/// its name in lower case.
+type Tally = BTreeMap<String, Karma>;
+
+/// A karma change found in a message.
+#[derive(Debug)]
+struct Change {
+    thing: String,
+    up: bool,
+    reason: Option<String>,
+}
+
+/// Records the karma changes in every message, whichever handler responds
+/// to it, in rooms where the `karma` handler is enabled. Edits aren't
+/// counted again, and nobody can change their own karma.
+#[derive(Debug)]
+pub struct KarmaTracker {
+    client: Client,
+    settings: Arc<RoomSettings>,
+    lock: Mutex<()>,
+    change_re: Regex,
+}
+
+impl KarmaTracker {
+    pub(crate) fn new(client: Client, settings: Arc<RoomSettings>) -> Self {
+        Self {
+            client,
+            settings,
+            lock: Mutex::new(()),
+            change_re: change_regex(),
+        }
+    }
+
+    /// Returns the key `thing` is tallied under: the user ID it is, or of a
+    /// member of `room` it names, or else the thing itself in lower case.
+    async fn resolve(&self, room: Option<&Joined>, thing: &str) -> String {
+        if thing.starts_with('@') && thing.contains(':') {
+            if let Ok(user_id) = UserId::try_from(thing) {
+                return user_id.to_string();
+            }
+        }
+
+        let name = thing.trim_start_matches('@');
+        if let Some(room) = room {
+            if let Ok(members) = room.active_members().await {
+                let member = members.into_iter().find(|m| {
+                    m.user_id().localpart().eq_ignore_ascii_case(name)
+                        || matches!(m.display_name(), Some(d) if d.eq_ignore_ascii_case(name))
+                });
+                if let Some(member) = member {
+                    return member.user_id().to_string();
+                }
+            }
+        }
+        thing.to_lowercase()
+    }
+
+    /// Applies a change to the room's and the global tallies.
+    async fn apply(&self, room_id: &RoomId, key: &str, change: &Change) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut room: Tally = store::load_room(&self.client, room_id, ROOM_NAME).await?;
+        let mut global: Tally = store::load(&self.client, GLOBAL_KEY).await?;
+
+        for tally in [&mut room, &mut global] {
+            let karma = tally.entry(key.into()).or_default();
+            karma.score += if change.up { 1 } else { -1 };
+            if let Some(reason) = &change.reason {
+                karma.reasons.push(reason.clone());
+                let excess = karma.reasons.len().saturating_sub(MAX_REASONS);
+                karma.reasons.drain(..excess);
+            }
+        }
+
+        store::save_room(&self.client, room_id, ROOM_NAME, &room).await?;
+        store::save(&self.client, GLOBAL_KEY, &global).await
+    }
+
+    async fn record(&self, room: Option<&Joined>, message: &Message) {
+        let mut seen = HashSet::new();
+        for change in changes(&self.change_re, &message.body) {
+            let key = self.resolve(room, &change.thing).await;
+            // `alice++ @alice++` only counts once
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            if key == message.sender.as_str() {
+                event!(Level::DEBUG, "{} tried to change their own karma", key);
+                continue;
+            }
+
+            match self.apply(&message.room_id, &key, &change).await {
+                Ok(()) => event!(Level::DEBUG, "changed karma for {}", key),
+                Err(e) => event!(Level::WARN, "failed to record karma for {}: {}", key, e),
+            }
+        }
+    }
+
+    async fn load(&self, room_id: &RoomId) -> Result<(Tally, Tally)> {
+        let room = store::load_room(&self.client, room_id, ROOM_NAME).await?;
+        let global = store::load(&self.client, GLOBAL_KEY).await?;
+        Ok((room, global))
+    }
+}
+
+#[async_trait]
+impl Middleware for KarmaTracker {
+    fn name(&self) -> &str {
+        "karma"
+    }
+
+    async fn before(&self, message: Message) -> Option<Message> {
+        // an edit would count the karma in the original message again
+        if message.kind == MessageKind::Text
+            && !message.is_edit
+            && self.settings.handler_enabled(&message.room_id, NAME)
+        {
+            let room = self.client.get_joined_room(&message.room_id);
+            self.record(room.as_ref(), &message).await;
+        }
+        Some(message)
+    }
+}
+
+/// Shows the karma [`KarmaTracker`] keeps, with `!karma <thing>` and the
+/// leaderboards.
+#[derive(Debug)]
+pub struct KarmaCounter {
+    client: Client,
+    karma: Arc<KarmaTracker>,
+    re: Regex,
+}
+
+impl KarmaCounter {
+    pub fn new(client: Client, karma: Arc<KarmaTracker>) -> Self {
+        Self {
+            client,
+            karma,
+            re: Regex::new(r"(?i)^(\s\*\s)?!karma(\s+(?P<arg>.+?))?\s*$").unwrap(),
+        }
+    }
+
+    /// How to show a key to people: a user's display name in `room` if
+    /// they have one, or the key itself.
+    async fn display(&self, room: &Joined, key: &str) -> String {
+        if let Ok(user_id) = UserId::try_from(key) {
+            if let Ok(Some(member)) = room.get_member(&user_id).await {
+                if let Some(name) = member.display_name() {
+                    return name.into();
+                }
+            }
+        }
+        key.into()
+    }
+
+    async fn show(&self, room: &Joined, thing: &str) -> String {
+        let key = self.karma.resolve(Some(room), thing).await;
+        let (here, global) = match self.karma.load(room.room_id()).await {
+            Ok(t) => t,
+            Err(e) => return format!("I couldn't read the karma: {}", e),
+        };
+
+        let name = self.display(room, &key).await;
+        let overall = match global.get(&key) {
+            Some(k) => k,
+            None => return format!("{} doesn't have any karma yet.", name),
+        };
+        let mut lines = vec![format!(
+            "{} has {} karma here ({} overall).",
+            name,
+            here.get(&key).map_or(0, |k| k.score),
+            overall.score
+        )];
+        if !overall.reasons.is_empty() {
+            lines.push("Recently, for:".into());
+            for reason in overall.reasons.iter().rev() {
+                lines.push(format!("* {}", reason));
+            }
+        }
+        lines.join("\n")
+    }
+
+    async fn top(&self, room: &Joined, global: bool) -> String {
+        let tally = match self.karma.load(room.room_id()).await {
+            Ok((_, t)) if global => t,
+            Ok((t, _)) => t,
+            Err(e) => return format!("I couldn't read the karma: {}", e),
+        };
+        if tally.is_empty() {
+            return "Nobody has any karma yet.".into();
+        }
+
+        let mut ranked: Vec<_> = tally.into_iter().collect();
+        ranked.sort_by(|(a, x), (b, y)| y.score.cmp(&x.score).then_with(|| a.cmp(b)));
+
+        let title = if global { "overall" } else { "in this room" };
+        let mut lines = vec![format!("Most karma {}:", title)];
+        for (i, (key, karma)) in ranked.into_iter().take(TOP).enumerate() {
+            lines.push(format!(
+                "{}. {}: {}",
+                i + 1,
+                self.display(room, &key).await,
+                karma.score
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[async_trait]
+impl Handler for KarmaCounter {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn cmd(&self) -> &str {
+        "!karma <thing|top [global]>"
+    }
+
+    fn description(&self) -> &str {
+        "Shows karma, given with `thing++` and taken with `thing--`"
+    }
+
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
+
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let room = self.client.get_joined_room(&m.message.room_id)?;
+        let response = match m.get("arg").map(|a| a.to_lowercase()).as_deref() {
+            None => "Usage: `!karma <thing>`, `!karma top` or `!karma top global`".into(),
+            Some("top") => self.top(&room, false).await,
+            Some("top global") => self.top(&room, true).await,
+            Some(_) => self.show(&room, m.get("arg")?).await,
+        };
+
+        super::new_message(response)
+    }
+}
+
+unsafe impl Sync for KarmaCounter {}
+unsafe impl Send for KarmaCounter {}
+
+/// The pattern of karma changes, such as `thing++` or `@user-- for reasons`.
+fn change_regex() -> Regex {
+    Regex::new(
+        r"(?i)(?:^|\s)(?P<thing>@?\w[\w.:\-]*?)(?P<op>\+\+|--)(?:\s+(?:for|because)\s+(?P<reason>.+))?",
+    )
+    .unwrap()
+}
+
+/// Finds the karma changes in `body`. A change must be followed by the end
+/// of the message, whitespace or punctuation, so that things like `a++b`
+/// don't count. Commands for other handlers, such as `!quote add C++ rocks`,
+/// never change karma. Each thing changes at most once, and only the first
+/// few changes in a message count.
+fn changes(re: &Regex, body: &str) -> Vec<Change> {
+    if body.trim_start().starts_with('!') {
+        return Vec::new();
+    }
+    let mut seen = HashSet::new();
+    re.captures_iter(body)
+        .filter(|caps| {
+            let end = caps.name("op").unwrap().end();
+            match body[end..].chars().next() {
+                Some(c) => c.is_whitespace() || (c.is_ascii_punctuation() && c != '+' && c != '-'),
+                None => true,
+            }
+        })
+        .map(|caps| Change {
+            thing: caps["thing"].trim_end_matches(['.', ':']).into(),
+            up: &caps["op"] == "++",
+            reason: caps.name("reason").map(|r| r.as_str().trim().to_string()),
+        })
+        .filter(|c| !c.thing.is_empty())
+        .filter(|c| seen.insert(c.thing.to_lowercase()))
+        .take(MAX_CHANGES)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::EventId;
+    use url::Url;
+
+    use super::*;
+    use crate::config::AccountConfig;
+    use crate::handlers::howdy::Howdy;
+    use crate::mentions::MentionDetector;
+    use crate::spaces::Spaces;
+    use crate::upgrades::RoomUpgrades;
+
+    fn parse(body: &str) -> Vec<(String, bool, Option<String>)> {
+        changes(&change_regex(), body)
+            .into_iter()
+            .map(|c| (c.thing, c.up, c.reason))
+            .collect()
+    }
+
+    #[test]
+    fn finds_changes() {
+        assert_eq!(parse("rust++"), [("rust".into(), true, None)]);
+        assert_eq!(
+            parse("meetings-- and coffee++!"),
+            [
+                ("meetings".into(), false, None),
+                ("coffee".into(), true, None)
+            ]
+        );
+        assert_eq!(
+            parse("@alice:example.org++ for fixing the build"),
+            [(
+                "@alice:example.org".into(),
+                true,
+                Some("fixing the build".into())
+            )]
+        );
+    }
+
+    #[test]
+    fn ignores_things_that_arent_changes() {
+        assert!(parse("a++b").is_empty());
+        assert!(parse("i---").is_empty());
+        assert!(parse("an arrow --> there").is_empty());
+        assert!(parse("nothing to see here").is_empty());
+        assert!(parse("!quote add C++ rocks").is_empty());
+        assert!(parse("!remind me in 1h to thank bob++").is_empty());
+    }
+
+    #[test]
+    fn counts_each_thing_once() {
+        assert_eq!(
+            parse("x++ X++ x-- y++"),
+            [("x".into(), true, None), ("y".into(), true, None)]
+        );
+        assert_eq!(parse("a++ b++ c++ d++ e++ f++ g++").len(), MAX_CHANGES);
+    }
+
+    fn message(body: &str) -> Message {
+        Message {
+            kind: MessageKind::Text,
+            room_id: RoomId::try_from("!room:example.org").unwrap(),
+            event_id: EventId::try_from("$event:example.org").unwrap(),
+            sender: UserId::try_from("@someone:example.org").unwrap(),
+            sender_name: "someone".into(),
+            body: body.into(),
+            formatted_body: None,
+            mentions: None,
+            is_edit: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn records_changes_whoever_responds() {
+        let account: AccountConfig = serde_json::from_value(serde_json::json!({
+            "homeserver": "https://example.org",
+        }))
+        .unwrap();
+        let account = Arc::new(account);
+        let client = Client::new(Url::parse("https://example.org").unwrap()).unwrap();
+        let settings = Arc::new(RoomSettings::new(
+            account.clone(),
+            Arc::new(RoomUpgrades::new(client.clone())),
+            Arc::new(Spaces::new(client.clone(), account)),
+        ));
+        let karma = Arc::new(KarmaTracker::new(client.clone(), settings));
+        let counter = KarmaCounter::new(client.clone(), karma.clone());
+        let bot = UserId::try_from("@bingo:example.org").unwrap();
+        let howdy = Howdy::new(client, Arc::new(MentionDetector::new(&bot, vec![])));
+
+        let greeting = message("hi all, rust++ and @someone:example.org++");
+        assert!(howdy.matches(&greeting).is_some());
+        assert!(counter.matches(&greeting).is_none());
+        assert!(karma.before(greeting.clone()).await.is_some());
+
+        let mut edit = greeting;
+        edit.is_edit = true;
+        karma.before(edit).await;
+
+        let (here, global) = karma.load(&message("").room_id).await.unwrap();
+        assert_eq!(here["rust"].score, 1);
+        assert_eq!(global["rust"].score, 1);
+        // someone can't give themselves karma
+        assert!(!here.contains_key("@someone:example.org"));
+
+        assert!(counter.matches(&message("!karma rust")).is_some());
+    }
+}
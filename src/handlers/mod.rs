@@ -1,59 +1,303 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use async_trait::async_trait;
+use matrix_sdk::room::Joined;
 use matrix_sdk::ruma::events::room::message::{
     MessageEventContent, MessageType, TextMessageEventContent,
 };
 use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
 use matrix_sdk::Client;
 use regex::Regex;
 
-use super::DISPLAY_NAME;
+use crate::config::{AccountConfig, CooldownConfig, Permission};
+use crate::errors::*;
+use crate::feedback::Feedback;
+use crate::history::History;
+use crate::ignore::IgnoreList;
+use crate::invites::Invites;
+use crate::joins::JoinQueue;
+use crate::mentions::MentionDetector;
+use crate::permissions::Permissions;
+use crate::responses::ResponseLog;
+use crate::scheduler::Scheduler;
+use crate::tasks::Tasks;
 
+mod admin;
 mod giphy;
 mod help;
 mod howdy;
+pub(crate) mod karma;
 mod python;
+pub(crate) mod quote;
+mod registry;
+mod remind;
 mod rfc;
 mod troutslap;
+mod undo;
 
+use admin::Admin;
 use giphy::Giphy;
 use help::Help;
 use howdy::Howdy;
+use karma::{KarmaCounter, KarmaTracker};
 use python::KyleHatesPython;
+use quote::Quotes;
+pub(crate) use registry::Outcome;
+pub use registry::Registry;
+use remind::Remind;
 use rfc::Rfc;
 use troutslap::TroutSlap;
+use undo::Undo;
 
 pub struct HelpInfo<'a> {
     pub command_name: &'a str,
     pub description: &'a str,
 }
 
+/// The kinds of message handlers can be given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Text,
+    /// An action, as sent with `/me`. The body doesn't include the sender.
+    Emote,
+    /// An automated message, usually from another bot.
+    Notice,
+    /// A reaction to one of the bot's responses, as seen by middleware. The
+    /// body is the reaction's emoji. Reactions go to handlers through
+    /// `on_reaction`, never `handle`.
+    Reaction,
+}
+
+/// An incoming message, as seen by handlers.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub kind: MessageKind,
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    pub sender: UserId,
+    /// The sender's display name, or their user ID if they don't have one.
+    pub sender_name: String,
+    pub body: String,
+    pub formatted_body: Option<String>,
+    /// The users listed in the message's `m.mentions`, if it has any.
+    pub mentions: Option<Vec<UserId>>,
+    /// Whether the message is an `m.replace` edit of an earlier one.
+    pub is_edit: bool,
+}
+
+/// A message a handler has claimed, with what its pattern captured.
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub message: Message,
+    /// The pattern's named capture groups that took part in the match.
+    pub captures: HashMap<String, String>,
+}
+
+impl Match {
+    /// Matches `re` against the message's body.
+    pub fn new(message: &Message, re: &Regex) -> Option<Self> {
+        let caps = re.captures(&message.body)?;
+        let captures = re
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), caps.name(name)?.as_str().to_string())))
+            .collect();
+
+        Some(Self {
+            message: message.clone(),
+            captures,
+        })
+    }
+
+    /// Claims the message without capturing anything.
+    pub fn whole(message: &Message) -> Self {
+        Self {
+            message: message.clone(),
+            captures: HashMap::new(),
+        }
+    }
+
+    /// Returns the named capture group, if it took part in the match.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.captures.get(name).map(String::as_str)
+    }
+}
+
+/// A reaction to one of the bot's responses.
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub sender: UserId,
+    /// The reaction's emoji, without any variation selector or skin tone.
+    pub key: String,
+    /// The message the reacted-to response answered.
+    pub trigger: Message,
+}
+
+/// What handlers are given when the bot starts, for work that isn't a
+/// response to a message.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub client: Client,
+    /// Background tasks, which the bot cancels when it stops.
+    pub tasks: Tasks,
+    /// Jobs that post to rooms at set times, across restarts.
+    pub scheduler: Arc<Scheduler>,
+}
+
+impl Context {
+    /// Posts `content` to a room the bot has joined.
+    pub async fn send(&self, room_id: &RoomId, content: AnyMessageEventContent) -> Result<EventId> {
+        let room = self
+            .client
+            .get_joined_room(room_id)
+            .ok_or_else(|| Error::BotError(format!("not joined to {}", room_id)))?;
+        Ok(room.send(content, None).await?.event_id)
+    }
+}
+
 #[async_trait]
 pub trait Handler: Send + Sync + std::fmt::Debug {
+    /// The name the handler is enabled by in the configuration.
+    fn name(&self) -> &str;
     fn cmd(&self) -> &str;
     fn description(&self) -> &str;
-    async fn handle(&self, sender: &str, message: &str) -> Option<AnyMessageEventContent>;
+
+    /// The pattern of messages the handler responds to. Patterns from every
+    /// handler are checked at once, and `matches` is only called for
+    /// handlers whose pattern matched, or that don't have one.
+    fn pattern(&self) -> Option<&Regex> {
+        None
+    }
+
+    /// Checks whether the handler claims `message`, before permissions and
+    /// cooldowns are applied and `handle` is called. By default, it claims
+    /// messages its pattern matches.
+    fn matches(&self, message: &Message) -> Option<Match> {
+        Match::new(message, self.pattern()?)
+    }
+
+    /// Responds to a message the handler claimed, if it has anything to say.
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent>;
+
+    /// How long the handler waits before responding again, unless the
+    /// account configures otherwise. No cooldown by default.
+    fn cooldown(&self) -> CooldownConfig {
+        CooldownConfig::default()
+    }
+
+    /// Who may use the handler, unless the account configures otherwise.
+    /// Anyone by default.
+    fn permission(&self) -> Permission {
+        Permission::Anyone
+    }
+
+    /// Returns whether the handler wants messages of this kind. Only plain
+    /// text by default; notices in particular are best left alone, since
+    /// answering other bots can start a loop.
+    fn accepts(&self, kind: MessageKind) -> bool {
+        kind == MessageKind::Text
+    }
+
+    /// Returns whether the handler's responses can be taken back with
+    /// `!undo` or ❌. Responses that can't aren't remembered, so they don't
+    /// get reactions either. True by default.
+    fn undoable(&self) -> bool {
+        true
+    }
+
+    /// Called when someone reacts to one of this handler's responses.
+    /// Returns a new response to send, if any.
+    async fn on_reaction(&self, _reaction: &Reaction) -> Option<AnyMessageEventContent> {
+        None
+    }
+
+    /// Called once the bot is ready to respond to messages. Handlers that do
+    /// work on their own schedule start it here, with `ctx.tasks`.
+    async fn on_start(&self, _ctx: &Context) {}
+
+    /// Called when the bot joins a room.
+    async fn on_room_join(&self, _room: &Joined) {}
+
+    /// Called when the bot shuts down, after its background tasks have been
+    /// cancelled.
+    async fn on_shutdown(&self) {}
+}
+
+/// The bot's shared state that some handlers need.
+#[derive(Debug, Clone)]
+pub struct BotState {
+    pub mentions: Arc<MentionDetector>,
+    pub invites: Arc<Invites>,
+    pub joins: Arc<JoinQueue>,
+    pub feedback: Arc<Feedback>,
+    pub responses: Arc<ResponseLog>,
+    pub scheduler: Arc<Scheduler>,
+    pub history: Arc<History>,
+    pub karma: Arc<KarmaTracker>,
+    pub permissions: Arc<Permissions>,
+    pub ignore: Arc<IgnoreList>,
 }
 
+/// Returns the handlers the account has enabled, or every handler if it
+/// doesn't list any.
 pub fn get_handlers(
     client: &Client,
     config: Option<&HashMap<String, String>>,
-) -> Vec<Box<dyn Handler>> {
-    vec![
-        Box::new(Help::new(client.clone())),
+    account: &AccountConfig,
+    state: BotState,
+) -> Registry {
+    let BotState {
+        mentions,
+        invites,
+        joins,
+        feedback,
+        responses,
+        scheduler,
+        history,
+        karma,
+        permissions,
+        ignore,
+    } = state;
+
+    let all: Vec<Box<dyn Handler>> = vec![
         Box::new(Giphy::new(client.clone(), config)),
-        Box::new(Howdy::new(client.clone())),
+        Box::new(Howdy::new(client.clone(), mentions.clone())),
+        Box::new(KarmaCounter::new(client.clone(), karma)),
         Box::new(KyleHatesPython::new(client.clone())),
+        Box::new(Quotes::new(client.clone(), history, permissions)),
+        Box::new(Remind::new(client.clone(), scheduler.clone())),
         Box::new(Rfc::new(client.clone())),
-        Box::new(TroutSlap::new(client.clone())),
-    ]
-}
+        Box::new(TroutSlap::new(client.clone(), mentions)),
+        Box::new(Undo::new(client.clone(), responses)),
+    ];
+
+    let mut handlers: Vec<Box<dyn Handler>> = all
+        .into_iter()
+        .filter(|h| account.handler_enabled(h.name()))
+        .collect();
+
+    if account.handler_enabled("help") {
+        let help = Help::new(client.clone(), &handlers);
+        handlers.insert(0, Box::new(help));
+    }
+
+    if !account.admins.is_empty() {
+        handlers.insert(
+            0,
+            Box::new(Admin::new(
+                client.clone(),
+                invites,
+                joins,
+                feedback,
+                scheduler,
+                ignore,
+            )),
+        );
+    }
 
-pub(crate) fn bot_mentioned(message: &str) -> bool {
-    Regex::new(&format!(r"(?i)\b{}\b", DISPLAY_NAME))
-        .unwrap()
-        .is_match(message)
+    Registry::new(handlers)
 }
 
 pub(crate) fn new_message(message: String) -> Option<AnyMessageEventContent> {
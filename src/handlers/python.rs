@@ -1,20 +1,28 @@
 use async_trait::async_trait;
 use matrix_sdk::ruma::events::AnyMessageEventContent;
-use tracing::{event, Level};
+use regex::Regex;
 
-use super::Handler;
+use super::{Handler, Match};
 
 #[derive(Debug, Clone)]
-pub struct KyleHatesPython {}
+pub struct KyleHatesPython {
+    re: Regex,
+}
 
 impl KyleHatesPython {
     pub fn new(_: matrix_sdk::Client) -> Self {
-        Self {}
+        Self {
+            re: Regex::new(r"(?i)python").unwrap(),
+        }
     }
 }
 
 #[async_trait]
 impl Handler for KyleHatesPython {
+    fn name(&self) -> &str {
+        "python"
+    }
+
     fn cmd(&self) -> &str {
         ""
     }
@@ -23,14 +31,11 @@ impl Handler for KyleHatesPython {
         ""
     }
 
-    async fn handle(&self, _sender: &str, message: &str) -> Option<AnyMessageEventContent> {
-        if !message.to_lowercase().contains("python") {
-            event!(Level::DEBUG, is_match = false);
-            return None;
-        }
-
-        event!(Level::DEBUG, is_match = true);
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
 
+    async fn handle(&self, _: &Match) -> Option<AnyMessageEventContent> {
         // respond to greetings only some of the time.
         if fastrand::f32() < 0.60 {
             return None;
@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::{RoomId, UserId};
+use matrix_sdk::Client;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::{Handler, Match};
+use crate::config::Permission;
+use crate::errors::*;
+use crate::history::History;
+use crate::permissions::{refusal, Permissions};
+use crate::store;
+
+pub(crate) const NAME: &str = "quote";
+
+/// The name of the permission `!quote delete` needs, so that accounts can
+/// configure it separately from the rest of the handler.
+const DELETE: &str = "quote.delete";
+
+/// The name each room's quotes are stored under.
+const ROOM_NAME: &str = "quotes";
+
+/// How many quotes a search lists.
+const MAX_RESULTS: usize = 5;
+
+/// A saved quote.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quote {
+    pub text: String,
+    /// Who said it, if known.
+    pub author: Option<String>,
+    pub added_by: UserId,
+    /// When it was added, in seconds since the Unix epoch.
+    pub added_at: u64,
+}
+
+impl Quote {
+    fn format(&self, id: u64) -> String {
+        match &self.author {
+            Some(author) => format!("#{}: {} — {}", id, self.text, author),
+            None => format!("#{}: {}", id, self.text),
+        }
+    }
+}
+
+/// A room's quotes, by number.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QuoteBook {
+    next_id: u64,
+    quotes: BTreeMap<u64, Quote>,
+}
+
+/// A quote database for each room. Quotes are added with `!quote add` or
+/// by grabbing someone's last message with `!grab`.
+#[derive(Debug)]
+pub struct Quotes {
+    client: Client,
+    lock: AsyncMutex<()>,
+    /// What people said recently, for `!grab`.
+    history: Arc<History>,
+    permissions: Arc<Permissions>,
+    re: Regex,
+    add_re: Regex,
+}
+
+impl Quotes {
+    pub fn new(client: Client, history: Arc<History>, permissions: Arc<Permissions>) -> Self {
+        Self {
+            client,
+            lock: AsyncMutex::new(()),
+            history,
+            permissions,
+            re: Regex::new(r"(?i)^(\s\*\s)?!(?P<cmd>quote|grab)\b\s*(?P<args>.*)$").unwrap(),
+            add_re: author_regex(),
+        }
+    }
+
+    async fn load(&self, room_id: &RoomId) -> Result<QuoteBook> {
+        store::load_room(&self.client, room_id, ROOM_NAME).await
+    }
+
+    async fn add(&self, room_id: &RoomId, quote: Quote) -> Result<u64> {
+        let _guard = self.lock.lock().await;
+        let mut book = self.load(room_id).await?;
+        let id = book.next_id + 1;
+        book.next_id = id;
+        book.quotes.insert(id, quote);
+        store::save_room(&self.client, room_id, ROOM_NAME, &book).await?;
+        Ok(id)
+    }
+
+    async fn add_text(&self, m: &Match, text: &str) -> String {
+        if text.is_empty() {
+            return "Usage: `!quote add <text>`, or `!quote add <name> text` to credit someone"
+                .into();
+        }
+        let (author, text) = credit(&self.add_re, text);
+
+        let quote = Quote {
+            text,
+            author,
+            added_by: m.message.sender.clone(),
+            added_at: now(),
+        };
+        match self.add(&m.message.room_id, quote).await {
+            Ok(id) => format!("Added quote #{}.", id),
+            Err(e) => format!("I couldn't save that quote: {}", e),
+        }
+    }
+
+    async fn grab(&self, m: &Match, user: &str) -> String {
+        if user.is_empty() {
+            return "Usage: `!grab <user>`".into();
+        }
+        let said = match self.history.last_said(&m.message.room_id, user) {
+            Some(s) => s,
+            None => return format!("I haven't heard {} say anything lately.", user),
+        };
+        if said.sender == m.message.sender {
+            return "You can't grab your own quotes.".into();
+        }
+
+        let quote = Quote {
+            text: said.text,
+            author: Some(said.sender_name),
+            added_by: m.message.sender.clone(),
+            added_at: now(),
+        };
+        match self.add(&m.message.room_id, quote).await {
+            Ok(id) => format!("Grabbed quote #{}.", id),
+            Err(e) => format!("I couldn't save that quote: {}", e),
+        }
+    }
+
+    async fn show(&self, room_id: &RoomId, id: Option<u64>) -> String {
+        let book = match self.load(room_id).await {
+            Ok(b) => b,
+            Err(e) => return format!("I couldn't read the quotes: {}", e),
+        };
+        if book.quotes.is_empty() {
+            return "There aren't any quotes here yet. Add one with `!quote add <text>`.".into();
+        }
+
+        let id = match id {
+            Some(id) => id,
+            None => *book
+                .quotes
+                .keys()
+                .nth(fastrand::usize(..book.quotes.len()))
+                .unwrap(),
+        };
+        match book.quotes.get(&id) {
+            Some(quote) => quote.format(id),
+            None => format!("There's no quote #{}.", id),
+        }
+    }
+
+    async fn search(&self, room_id: &RoomId, term: &str) -> String {
+        if term.is_empty() {
+            return "Usage: `!quote search <term>`".into();
+        }
+        let book = match self.load(room_id).await {
+            Ok(b) => b,
+            Err(e) => return format!("I couldn't read the quotes: {}", e),
+        };
+
+        let term = term.to_lowercase();
+        let found: Vec<_> = book
+            .quotes
+            .iter()
+            .filter(|(_, q)| {
+                q.text.to_lowercase().contains(&term)
+                    || matches!(&q.author, Some(a) if a.to_lowercase().contains(&term))
+            })
+            .collect();
+        if found.is_empty() {
+            return format!("No quotes mention \"{}\".", term);
+        }
+
+        let mut lines: Vec<String> = found
+            .iter()
+            .take(MAX_RESULTS)
+            .map(|(id, q)| format!("* {}", q.format(**id)))
+            .collect();
+        if found.len() > MAX_RESULTS {
+            lines.push(format!("…and {} more.", found.len() - MAX_RESULTS));
+        }
+        lines.join("\n")
+    }
+
+    async fn delete(&self, room: &Joined, m: &Match, id: &str) -> String {
+        let id: u64 = match id.trim_start_matches('#').parse() {
+            Ok(id) => id,
+            Err(_) => return "Usage: `!quote delete <number>`".into(),
+        };
+        // moderators only, unless the account says otherwise
+        let moderators = Permission::PowerLevel(crate::MODERATOR_POWER_LEVEL);
+        if !self
+            .permissions
+            .may(DELETE, moderators, room, &m.message.sender)
+            .await
+        {
+            return refusal(&m.message.sender_name);
+        }
+
+        let _guard = self.lock.lock().await;
+        let result = async {
+            let mut book = self.load(room.room_id()).await?;
+            let found = book.quotes.remove(&id).is_some();
+            if found {
+                store::save_room(&self.client, room.room_id(), ROOM_NAME, &book).await?;
+            }
+            Ok::<_, Error>(found)
+        };
+        match result.await {
+            Ok(true) => format!("Deleted quote #{}.", id),
+            Ok(false) => format!("There's no quote #{}.", id),
+            Err(e) => format!("That didn't work: {}", e),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Quotes {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn cmd(&self) -> &str {
+        "!quote [number|add <text>|search <term>|delete <number>], !grab <user>"
+    }
+
+    fn description(&self) -> &str {
+        "Saves and recalls memorable quotes"
+    }
+
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
+
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let args = m.get("args").unwrap_or_default();
+        let room_id = &m.message.room_id;
+
+        let response = match command(m.get("cmd")?, args) {
+            Command::Grab(user) => self.grab(m, user).await,
+            Command::Random => self.show(room_id, None).await,
+            Command::Show(id) => self.show(room_id, Some(id)).await,
+            Command::Add(text) => self.add_text(m, text).await,
+            Command::Search(term) => self.search(room_id, term).await,
+            Command::Delete(id) => {
+                let room = self.client.get_joined_room(room_id)?;
+                self.delete(&room, m, id).await
+            }
+            Command::Unknown(sub) => format!("I don't know how to `!quote {}`.", sub),
+        };
+
+        super::new_message(response)
+    }
+}
+
+unsafe impl Sync for Quotes {}
+unsafe impl Send for Quotes {}
+
+/// What a `!quote` or `!grab` command asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    Grab(&'a str),
+    Random,
+    Show(u64),
+    Add(&'a str),
+    Search(&'a str),
+    Delete(&'a str),
+    Unknown(String),
+}
+
+/// Works out what `!<cmd> <args>` asks for.
+fn command<'a>(cmd: &str, args: &'a str) -> Command<'a> {
+    let args = args.trim();
+    if cmd.eq_ignore_ascii_case("grab") {
+        return Command::Grab(args);
+    }
+    let (sub, rest) = match args.split_once(char::is_whitespace) {
+        Some((sub, rest)) => (sub.to_lowercase(), rest.trim()),
+        None => (args.to_lowercase(), ""),
+    };
+    match &sub[..] {
+        "" => Command::Random,
+        "add" => Command::Add(rest),
+        "search" => Command::Search(rest),
+        "delete" | "del" | "remove" => Command::Delete(rest),
+        id => match id.trim_start_matches('#').parse() {
+            Ok(id) => Command::Show(id),
+            Err(_) => Command::Unknown(sub),
+        },
+    }
+}
+
+/// The pattern of a quote credited to someone, such as `<alice> hello`.
+fn author_regex() -> Regex {
+    Regex::new(r"^<(?P<author>[^>]+)>\s*(?P<text>.+)$").unwrap()
+}
+
+/// Splits the author, if there is one, from the text of a quote.
+fn credit(re: &Regex, text: &str) -> (Option<String>, String) {
+    match re.captures(text) {
+        Some(caps) => (
+            Some(caps["author"].trim().to_string()),
+            caps["text"].to_string(),
+        ),
+        None => (None, text.to_string()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credits_authors() {
+        let re = author_regex();
+        assert_eq!(
+            credit(&re, "<alice> it works on my machine"),
+            (Some("alice".into()), "it works on my machine".into())
+        );
+        assert_eq!(
+            credit(&re, "< Bob Smith >ship it"),
+            (Some("Bob Smith".into()), "ship it".into())
+        );
+        assert_eq!(credit(&re, "a <b> tag"), (None, "a <b> tag".into()));
+        assert_eq!(credit(&re, "<alice>"), (None, "<alice>".into()));
+    }
+
+    #[test]
+    fn dispatches_commands() {
+        assert_eq!(command("grab", " alice "), Command::Grab("alice"));
+        assert_eq!(command("quote", ""), Command::Random);
+        assert_eq!(command("quote", "42"), Command::Show(42));
+        assert_eq!(command("QUOTE", "#7"), Command::Show(7));
+        assert_eq!(
+            command("quote", "ADD <alice> hi  there"),
+            Command::Add("<alice> hi  there")
+        );
+        assert_eq!(command("quote", "search rust"), Command::Search("rust"));
+        assert_eq!(command("quote", "del #3"), Command::Delete("#3"));
+        assert_eq!(command("quote", "remove 3"), Command::Delete("3"));
+        assert_eq!(
+            command("quote", "frobnicate 3"),
+            Command::Unknown("frobnicate".into())
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::sync::Arc;
+
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use regex::{RegexSet, RegexSetBuilder};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tracing::{event, Level};
+
+use super::{Handler, Match, Message};
+use crate::config::TimeoutConfig;
+use crate::shutdown::Task;
+
+/// The `regex` crate's default size limit for a single compiled regex.
+const PATTERN_SIZE_LIMIT: usize = 10 * (1 << 20);
+
+/// What came of running a handler for a message.
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    Responded(AnyMessageEventContent),
+    /// The handler had nothing to say, or failed.
+    Silent,
+    TimedOut,
+}
+
+/// The bot's handlers, in priority order, with their patterns compiled
+/// together so that a message is matched against all of them in one pass.
+#[derive(Debug)]
+pub struct Registry {
+    handlers: Vec<Box<dyn Handler>>,
+    set: RegexSet,
+    /// The handler behind each pattern in `set`.
+    patterned: Vec<usize>,
+}
+
+impl Registry {
+    pub fn new(handlers: Vec<Box<dyn Handler>>) -> Self {
+        let (patterned, patterns): (Vec<_>, Vec<_>) = handlers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, h)| Some((i, h.pattern()?.as_str())))
+            .unzip();
+        // Each pattern already compiled on its own within the default size
+        // limit, so they fit together within the sum of their limits.
+        let limit = PATTERN_SIZE_LIMIT * patterns.len().max(1);
+        let set = RegexSetBuilder::new(patterns)
+            .size_limit(limit)
+            .build()
+            .expect("handler patterns should compile together within their combined size limit");
+
+        Self {
+            handlers,
+            set,
+            patterned,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Handler> {
+        self.handlers.iter().map(|h| h.as_ref())
+    }
+
+    pub fn get(&self, index: usize) -> &dyn Handler {
+        self.handlers[index].as_ref()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&dyn Handler> {
+        self.iter().find(|h| h.name() == name)
+    }
+
+    /// Returns the handlers that claim `message`, in priority order, by
+    /// index.
+    pub fn claims<'a>(&'a self, message: &'a Message) -> impl Iterator<Item = (usize, Match)> + 'a {
+        let mut candidates = vec![false; self.handlers.len()];
+        for (i, h) in self.handlers.iter().enumerate() {
+            candidates[i] = h.pattern().is_none();
+        }
+        for m in self.set.matches(&message.body).iter() {
+            candidates[self.patterned[m]] = true;
+        }
+
+        self.handlers
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| candidates[*i])
+            .filter_map(move |(i, h)| {
+                let m = h.matches(message)?;
+                event!(Level::DEBUG, handler = h.name(), is_match = true);
+                Some((i, m))
+            })
+    }
+
+    /// Starts `handle` for every claimant at once, each under its own
+    /// timeout, and returns their outcomes in the claimants' order. A handler
+    /// that overruns is left to finish in the background, holding `task`, so
+    /// that it's never cut off halfway through a change; only its response
+    /// is dropped.
+    pub(crate) fn run(
+        self: &Arc<Self>,
+        claimants: Vec<(usize, Match)>,
+        timeouts: &TimeoutConfig,
+        task: &Task,
+    ) -> Vec<(usize, JoinHandle<Outcome>)> {
+        claimants
+            .into_iter()
+            .map(|(i, m)| {
+                let name = self.get(i).name().to_string();
+                let limit = timeouts.get(&name);
+                let mut handle = {
+                    let handlers = self.clone();
+                    let task = task.clone();
+                    tokio::spawn(async move {
+                        let response = handlers.get(i).handle(&m).await;
+                        drop(task);
+                        response
+                    })
+                };
+                let outcome = tokio::spawn(async move {
+                    match timeout(Duration::from_secs(limit), &mut handle).await {
+                        Ok(Ok(Some(r))) => Outcome::Responded(r),
+                        Ok(Ok(None)) => Outcome::Silent,
+                        Ok(Err(e)) => {
+                            event!(Level::ERROR, "{} failed: {}", name, e);
+                            Outcome::Silent
+                        }
+                        Err(_) => {
+                            event!(Level::WARN, "{} timed out after {}s", name, limit);
+                            Outcome::TimedOut
+                        }
+                    }
+                });
+                (i, outcome)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use async_trait::async_trait;
+    use matrix_sdk::ruma::{EventId, RoomId, UserId};
+    use regex::Regex;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::handlers::MessageKind;
+
+    /// A handler claiming messages its pattern matches, or if it has none,
+    /// messages containing its name.
+    #[derive(Debug)]
+    struct Test(&'static str, Option<Regex>);
+
+    #[async_trait]
+    impl Handler for Test {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn cmd(&self) -> &str {
+            ""
+        }
+
+        fn description(&self) -> &str {
+            ""
+        }
+
+        fn pattern(&self) -> Option<&Regex> {
+            self.1.as_ref()
+        }
+
+        fn matches(&self, message: &Message) -> Option<Match> {
+            match &self.1 {
+                Some(re) => Match::new(message, re),
+                None => message.body.contains(self.0).then(|| Match::whole(message)),
+            }
+        }
+
+        async fn handle(&self, _m: &Match) -> Option<AnyMessageEventContent> {
+            None
+        }
+    }
+
+    fn registry() -> Registry {
+        let pattern = |re: &str| Some(Regex::new(re).unwrap());
+        Registry::new(vec![
+            Box::new(Test("plain", None)),
+            Box::new(Test("alpha", pattern(r"^!alpha (?P<arg>\w+)"))),
+            Box::new(Test("loose", None)),
+            Box::new(Test("beta", pattern(r"(?P<word>beta\d*)"))),
+            Box::new(Test("gamma", pattern(r"^!gamma$"))),
+        ])
+    }
+
+    fn message(body: &str) -> Message {
+        Message {
+            kind: MessageKind::Text,
+            room_id: RoomId::try_from("!room:example.org").unwrap(),
+            event_id: EventId::try_from("$event:example.org").unwrap(),
+            sender: UserId::try_from("@someone:example.org").unwrap(),
+            sender_name: "someone".into(),
+            body: body.into(),
+            formatted_body: None,
+            mentions: None,
+            is_edit: false,
+        }
+    }
+
+    fn claims(registry: &Registry, body: &str) -> Vec<(usize, Vec<(String, String)>)> {
+        let message = message(body);
+        registry
+            .claims(&message)
+            .map(|(i, m)| {
+                let mut captures: Vec<_> = m.captures.into_iter().collect();
+                captures.sort();
+                (i, captures)
+            })
+            .collect()
+    }
+
+    /// A handler that claims everything, and responds with its name after
+    /// a while.
+    #[derive(Debug)]
+    struct Sleepy(&'static str, Duration);
+
+    #[async_trait]
+    impl Handler for Sleepy {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn cmd(&self) -> &str {
+            ""
+        }
+
+        fn description(&self) -> &str {
+            ""
+        }
+
+        fn matches(&self, message: &Message) -> Option<Match> {
+            Some(Match::whole(message))
+        }
+
+        async fn handle(&self, _m: &Match) -> Option<AnyMessageEventContent> {
+            tokio::time::sleep(self.1).await;
+            crate::handlers::new_message(self.0.into())
+        }
+    }
+
+    fn capture(name: &str, value: &str) -> (String, String) {
+        (name.into(), value.into())
+    }
+
+    #[test]
+    fn maps_patterns_back_to_handlers() {
+        let registry = registry();
+
+        assert_eq!(
+            claims(&registry, "!alpha one beta2"),
+            [
+                (1, vec![capture("arg", "one")]),
+                (3, vec![capture("word", "beta2")])
+            ]
+        );
+        assert_eq!(
+            claims(&registry, "plain loose beta"),
+            [(0, vec![]), (2, vec![]), (3, vec![capture("word", "beta")])]
+        );
+        assert_eq!(claims(&registry, "!gamma"), [(4, vec![])]);
+        assert!(claims(&registry, "nothing here").is_empty());
+    }
+
+    #[tokio::test]
+    async fn runs_claimants_at_once() {
+        let registry = Arc::new(Registry::new(vec![
+            Box::new(Sleepy("slow", Duration::from_secs(60))),
+            Box::new(Sleepy("fast", Duration::from_millis(800))),
+        ]));
+        let timeouts = TimeoutConfig {
+            default: 1,
+            ..TimeoutConfig::default()
+        };
+        let in_flight = crate::shutdown::InFlight::new();
+        let task = in_flight.start().unwrap();
+        let message = message("anything");
+        let claimants = registry.claims(&message).collect();
+
+        let started = Instant::now();
+        let mut runs = registry.run(claimants, &timeouts, &task).into_iter();
+        let (slow, run) = runs.next().unwrap();
+        assert_eq!(slow, 0);
+        assert!(matches!(run.await.unwrap(), Outcome::TimedOut));
+        let (fast, run) = runs.next().unwrap();
+        assert_eq!(fast, 1);
+        assert!(matches!(run.await.unwrap(), Outcome::Responded(_)));
+
+        // one after the other, they'd have taken 1.8s
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+}
@@ -0,0 +1,397 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use chrono_tz::Tz;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Client;
+use regex::Regex;
+
+use super::{Handler, Match};
+use crate::scheduler::{Schedule, Scheduler};
+
+const NAME: &str = "remind";
+
+/// The most reminders one person may have waiting.
+const MAX_REMINDERS: usize = 25;
+
+/// The furthest ahead a reminder may be set, in days.
+const MAX_DAYS_AHEAD: i64 = 366;
+
+/// The hour reminders set for "tomorrow" go off, when no time is given.
+const DEFAULT_HOUR: u32 = 9;
+
+const USAGE: &str =
+    "Try `!remind me in 2h to deploy` or `!remind #room at 09:00 tomorrow standup`.";
+
+/// Sets reminders that mention the requester when they go off.
+#[derive(Debug)]
+pub struct Remind {
+    client: Client,
+    scheduler: Arc<Scheduler>,
+    times: TimeParser,
+    re: Regex,
+    target_re: Regex,
+    cancel_re: Regex,
+}
+
+impl Remind {
+    pub fn new(client: Client, scheduler: Arc<Scheduler>) -> Self {
+        Self {
+            client,
+            scheduler,
+            times: TimeParser::new(),
+            re: Regex::new(r"(?i)^(\s\*\s)?!(?P<cmd>reminders|remind)\b\s*(?P<args>.*)$").unwrap(),
+            target_re: Regex::new(r"(?i)^(?P<target>me|[#!]\S+)\s+(?P<rest>.+)$").unwrap(),
+            cancel_re: Regex::new(r"(?i)^cancel\s+#?(?P<id>[0-9]+)\s*$").unwrap(),
+        }
+    }
+
+    async fn remind(&self, m: &Match, args: &str) -> String {
+        let caps = match self.target_re.captures(args) {
+            Some(c) => c,
+            None => return USAGE.into(),
+        };
+        let sender = &m.message.sender;
+
+        let target = &caps["target"];
+        let room = if target.eq_ignore_ascii_case("me") {
+            self.client.get_joined_room(&m.message.room_id)
+        } else {
+            self.find_room(target)
+        };
+        let room = match room {
+            Some(r) => r,
+            None => return format!("I'm not in {}.", target),
+        };
+        if !matches!(room.get_member(sender).await, Ok(Some(_))) {
+            return format!("You're not in {}.", target);
+        }
+
+        let tz = self.scheduler.timezone();
+        let now = Utc::now().with_timezone(&tz);
+        let (at, text) = match self.times.parse(&caps["rest"], now) {
+            Some(w) => w,
+            None => return format!("I couldn't tell when you meant. {}", USAGE),
+        };
+        if text.is_empty() {
+            return "What should I remind you about?".into();
+        }
+        if at > (now + Duration::days(MAX_DAYS_AHEAD)).with_timezone(&Utc) {
+            return "That's too far away for me to remember.".into();
+        }
+
+        match self.reminders(sender).await {
+            Ok(r) if r.len() >= MAX_REMINDERS => {
+                let cancel = "Cancel one with `!reminders cancel <ID>` first.";
+                return format!("You already have {} reminders. {}", r.len(), cancel);
+            }
+            Ok(_) => {}
+            Err(e) => return format!("That didn't work: {}", e),
+        }
+
+        let message = format!("{}: {}", mention(sender), text);
+        let scheduled = self
+            .scheduler
+            .once(room.room_id(), NAME, Some(sender), at, &message)
+            .await;
+        match scheduled {
+            Ok(id) => format!(
+                "Okay, I'll remind you at {}. (#{})",
+                at.with_timezone(&tz).format("%Y-%m-%d %H:%M %Z"),
+                id
+            ),
+            Err(e) => format!("That didn't work: {}", e),
+        }
+    }
+
+    async fn list(&self, sender: &UserId) -> String {
+        let reminders = match self.reminders(sender).await {
+            Ok(r) if r.is_empty() => return "You don't have any reminders.".into(),
+            Ok(r) => r,
+            Err(e) => return format!("I couldn't read your reminders: {}", e),
+        };
+
+        let tz = self.scheduler.timezone();
+        let prefix = format!("{}: ", mention(sender));
+        let mut lines = vec!["Your reminders:".to_string()];
+        for (id, at, room, message) in reminders {
+            lines.push(format!(
+                "* #{} at {} in {}: {}",
+                id,
+                at.with_timezone(&tz).format("%Y-%m-%d %H:%M %Z"),
+                room,
+                message.strip_prefix(&prefix).unwrap_or(&message)
+            ));
+        }
+        lines.push("Cancel one with `!reminders cancel <ID>`.".into());
+        lines.join("\n")
+    }
+
+    async fn cancel(&self, sender: &UserId, id: u64) -> String {
+        match self.reminders(sender).await {
+            Ok(r) if !r.iter().any(|(i, ..)| *i == id) => {
+                return format!("You don't have a reminder #{}.", id)
+            }
+            Ok(_) => {}
+            Err(e) => return format!("That didn't work: {}", e),
+        }
+
+        match self.scheduler.cancel(id).await {
+            Ok(true) => format!("Cancelled reminder #{}.", id),
+            Ok(false) => format!("You don't have a reminder #{}.", id),
+            Err(e) => format!("That didn't work: {}", e),
+        }
+    }
+
+    /// Returns `sender`'s reminders, soonest first, as their ID, time, room,
+    /// and message.
+    async fn reminders(
+        &self,
+        sender: &UserId,
+    ) -> crate::Result<Vec<(u64, DateTime<Utc>, String, String)>> {
+        let mut reminders: Vec<_> = self
+            .scheduler
+            .jobs()
+            .await?
+            .into_iter()
+            .filter(|(_, job)| job.handler == NAME && job.owner.as_ref() == Some(sender))
+            .filter_map(|(id, job)| match job.schedule {
+                Schedule::Once(at) => Some((
+                    id,
+                    Utc.timestamp(at, 0),
+                    job.room_id.to_string(),
+                    job.message,
+                )),
+                Schedule::Cron { .. } => None,
+            })
+            .collect();
+        reminders.sort_by_key(|(_, at, ..)| *at);
+        Ok(reminders)
+    }
+
+    /// Finds a joined room by ID or alias. Aliases may leave out the server
+    /// name.
+    fn find_room(&self, target: &str) -> Option<Joined> {
+        self.client.joined_rooms().into_iter().find(|room| {
+            if room.room_id().as_str() == target {
+                return true;
+            }
+            match room.canonical_alias() {
+                Some(alias) if target.contains(':') => alias.as_str() == target,
+                Some(alias) => alias.as_str().split(':').next() == Some(target),
+                None => false,
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl Handler for Remind {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn cmd(&self) -> &str {
+        "!remind <me|#room> <when> <what>"
+    }
+
+    fn description(&self) -> &str {
+        "Reminds you of something later. `!reminders` lists your reminders"
+    }
+
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
+
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let args = m.get("args").unwrap_or_default().trim();
+        let sender = &m.message.sender;
+        let response = match &m.get("cmd")?.to_lowercase()[..] {
+            "reminders" if args.is_empty() => self.list(sender).await,
+            "reminders" => match self.cancel_re.captures(args) {
+                Some(caps) => match caps["id"].parse() {
+                    Ok(id) => self.cancel(sender, id).await,
+                    Err(_) => format!("You don't have a reminder #{}.", &caps["id"]),
+                },
+                None => "Usage: `!reminders` or `!reminders cancel <ID>`".into(),
+            },
+            _ => self.remind(m, args).await,
+        };
+
+        super::new_message(response)
+    }
+}
+
+unsafe impl Sync for Remind {}
+unsafe impl Send for Remind {}
+
+/// A link to `user` that Matrix clients show as a mention.
+fn mention(user: &UserId) -> String {
+    format!("[{}](https://matrix.to/#/{})", user, user)
+}
+
+/// Understands when a reminder is for: "in 2h", "in 1 day and 3 hours",
+/// "at 17:30", "at 9am tomorrow", "tomorrow at 09:00", or just "tomorrow".
+#[derive(Debug)]
+struct TimeParser {
+    in_re: Regex,
+    span_re: Regex,
+    at_re: Regex,
+    tomorrow_re: Regex,
+}
+
+impl TimeParser {
+    fn new() -> Self {
+        Self {
+            in_re: Regex::new(
+                r"(?i)^in\s+(?P<spans>(?:[0-9]+\s*[a-z]+(?:\s*,\s*|\s+and\s+|\s+)?)+)(?:\s+|$)",
+            )
+            .unwrap(),
+            span_re: Regex::new(r"(?i)(?P<n>[0-9]+)\s*(?P<unit>[a-z]+)").unwrap(),
+            at_re: Regex::new(
+                r"(?i)^(?:(?P<day1>today|tomorrow)\s+)?at\s+(?P<hour>[0-9]{1,2})(?::(?P<minute>[0-9]{2}))?\s*(?P<ampm>am|pm)?(?:\s+(?P<day2>today|tomorrow))?(?:\s+|$)",
+            )
+            .unwrap(),
+            tomorrow_re: Regex::new(r"(?i)^tomorrow(?:\s+|$)").unwrap(),
+        }
+    }
+
+    /// Parses the time at the start of `text`, relative to `now`. Returns
+    /// the time and the rest of the text, without any leading "to". Delays
+    /// longer than `MAX_DAYS_AHEAD` aren't understood.
+    fn parse<'a>(&self, text: &'a str, now: DateTime<Tz>) -> Option<(DateTime<Utc>, &'a str)> {
+        let (at, end) = if let Some(caps) = self.in_re.captures(text) {
+            let mut seconds: i64 = 0;
+            for span in self.span_re.captures_iter(&caps["spans"]) {
+                let n: i64 = span["n"].parse().ok()?;
+                seconds = unit_seconds(&span["unit"])?
+                    .checked_mul(n)?
+                    .checked_add(seconds)
+                    .filter(|s| *s <= MAX_DAYS_AHEAD * 24 * 60 * 60)?;
+            }
+            let at = now.checked_add_signed(Duration::seconds(seconds))?;
+            (at, caps.get(0)?.end())
+        } else if let Some(caps) = self.at_re.captures(text) {
+            let mut hour: u32 = caps["hour"].parse().ok()?;
+            let minute: u32 = match caps.name("minute") {
+                Some(m) => m.as_str().parse().ok()?,
+                None => 0,
+            };
+            match caps.name("ampm").map(|m| m.as_str().to_lowercase()) {
+                Some(_) if hour == 0 || hour > 12 => return None,
+                Some(ampm) if ampm == "am" => hour %= 12,
+                Some(_) => hour = hour % 12 + 12,
+                None => {}
+            }
+            let day = caps.name("day1").or_else(|| caps.name("day2"));
+            let at = match day.map(|d| d.as_str().to_lowercase()) {
+                Some(d) if d == "tomorrow" => local_time(now, 1, hour, minute)?,
+                Some(_) => local_time(now, 0, hour, minute).filter(|t| *t > now)?,
+                // a time that's already passed today means tomorrow
+                None => match local_time(now, 0, hour, minute)? {
+                    t if t > now => t,
+                    _ => local_time(now, 1, hour, minute)?,
+                },
+            };
+            (at, caps.get(0)?.end())
+        } else {
+            let caps = self.tomorrow_re.captures(text)?;
+            let at = local_time(now, 1, DEFAULT_HOUR, 0)?;
+            (at, caps.get(0)?.end())
+        };
+
+        if at <= now {
+            return None;
+        }
+        let rest = text[end..].trim();
+        let rest = match rest.get(..3) {
+            Some(to) if to.eq_ignore_ascii_case("to ") => rest[3..].trim_start(),
+            _ => rest,
+        };
+        Some((at.with_timezone(&Utc), rest))
+    }
+}
+
+/// The length of a unit of time, in seconds.
+fn unit_seconds(unit: &str) -> Option<i64> {
+    match &unit.to_lowercase()[..] {
+        "s" | "sec" | "secs" | "second" | "seconds" => Some(1),
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(60),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(3600),
+        "d" | "day" | "days" => Some(86400),
+        "w" | "week" | "weeks" => Some(604800),
+        _ => None,
+    }
+}
+
+/// Returns `hour:minute` local time, `days` days after `now`. Times skipped
+/// by a clock change don't exist, and the earlier of times that happen
+/// twice is used.
+fn local_time(now: DateTime<Tz>, days: i64, hour: u32, minute: u32) -> Option<DateTime<Tz>> {
+    let date = now.naive_local().date() + Duration::days(days);
+    let naive = date.and_hms_opt(hour, minute, 0)?;
+    now.timezone().from_local_datetime(&naive).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2021-06-01 14:00 in London, which is on summer time.
+    fn now() -> DateTime<Tz> {
+        Utc.ymd(2021, 6, 1)
+            .and_hms(13, 0, 0)
+            .with_timezone(&chrono_tz::Europe::London)
+    }
+
+    fn parse(text: &str) -> Option<(DateTime<Utc>, &str)> {
+        TimeParser::new().parse(text, now())
+    }
+
+    fn utc(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2021, 6, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn parses_delays() {
+        assert_eq!(parse("in 2h to deploy"), Some((utc(1, 15, 0), "deploy")));
+        assert_eq!(parse("in 1h30m stretch"), Some((utc(1, 14, 30), "stretch")));
+        assert_eq!(
+            parse("in 1 day and 2 hours to call mum"),
+            Some((utc(2, 15, 0), "call mum"))
+        );
+        assert_eq!(parse("in 45 minutes"), Some((utc(1, 13, 45), "")));
+    }
+
+    #[test]
+    fn parses_local_times() {
+        assert_eq!(parse("at 17:30 leave"), Some((utc(1, 16, 30), "leave")));
+        assert_eq!(parse("at 5pm leave"), Some((utc(1, 16, 0), "leave")));
+        // already passed today
+        assert_eq!(parse("at 09:00 standup"), Some((utc(2, 8, 0), "standup")));
+        assert_eq!(
+            parse("at 09:00 tomorrow standup"),
+            Some((utc(2, 8, 0), "standup"))
+        );
+        assert_eq!(
+            parse("tomorrow at 12am midnight"),
+            Some((utc(1, 23, 0), "midnight"))
+        );
+        assert_eq!(parse("tomorrow standup"), Some((utc(2, 8, 0), "standup")));
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        assert_eq!(parse("in 2 hats to deploy"), None);
+        assert_eq!(parse("at 25:00 sleep"), None);
+        assert_eq!(parse("at 13pm lunch"), None);
+        assert_eq!(parse("at 09:00 today standup"), None);
+        assert_eq!(parse("whenever deploy"), None);
+        assert_eq!(parse("in 10000000000000000s panic"), None);
+        assert_eq!(parse("in 9223372036854775807 weeks panic"), None);
+        assert_eq!(parse("in 400 days and 1s too late"), None);
+    }
+}
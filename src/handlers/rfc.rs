@@ -3,7 +3,7 @@ use matrix_sdk::ruma::events::AnyMessageEventContent;
 use regex::Regex;
 use tracing::{event, Level};
 
-use super::Handler;
+use super::{Handler, Match};
 
 #[derive(Debug, Clone)]
 pub struct Rfc {
@@ -13,13 +13,17 @@ pub struct Rfc {
 impl Rfc {
     pub fn new(_: matrix_sdk::Client) -> Self {
         Self {
-            re: Regex::new(r"(?i)^(\s\*\s)?!rfc:?\s+(?P<num>[0-9]+)$").unwrap(),
+            re: Regex::new(r"^(\s\*\s)?!rfc(:?\s+(?P<num>[0-9]+)$)?").unwrap(),
         }
     }
 }
 
 #[async_trait]
 impl Handler for Rfc {
+    fn name(&self) -> &str {
+        "rfc"
+    }
+
     fn cmd(&self) -> &str {
         "!rfc <number>"
     }
@@ -28,25 +32,23 @@ impl Handler for Rfc {
         "Generates a link to an RFC"
     }
 
-    async fn handle(&self, _: &str, message: &str) -> Option<AnyMessageEventContent> {
-        if !(message.starts_with("!rfc") || message.starts_with(" * !rfc")) {
-            event!(Level::DEBUG, is_match = false);
-            return None;
-        }
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
 
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
         let responses = &[
             String::from("that's not an rfc, my dude"),
             String::from("what even is that because it's not an rfc"),
             String::from("no. just no"),
         ];
-        let mut resp = responses[fastrand::usize(..responses.len())].clone();
-
-        if let Some(captures) = self.re.captures(message) {
-            if let Some(rfc) = captures.name("num") {
-                event!(Level::DEBUG, is_match = true);
-                resp = format!("https://tools.ietf.org/html/rfc{}", rfc.as_str());
+        let resp = match m.get("num") {
+            Some(rfc) => format!("https://tools.ietf.org/html/rfc{}", rfc),
+            None => {
+                event!(Level::DEBUG, "not an RFC number");
+                responses[fastrand::usize(..responses.len())].clone()
             }
-        }
+        };
 
         super::new_message(resp)
     }
@@ -1,25 +1,48 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use matrix_sdk::ruma::events::AnyMessageEventContent;
 use regex::Regex;
-use tracing::{event, Level};
 
-use super::{bot_mentioned, Handler};
+use super::{Handler, Match, Message, MessageKind};
+use crate::config::CooldownConfig;
+use crate::mentions::MentionDetector;
 
 #[derive(Debug, Clone)]
 pub struct TroutSlap {
+    mentions: Arc<MentionDetector>,
     re: Regex,
 }
 
 impl TroutSlap {
-    pub fn new(_: matrix_sdk::Client) -> Self {
+    pub fn new(_: matrix_sdk::Client, mentions: Arc<MentionDetector>) -> Self {
         Self {
-            re: Regex::new(r"(?i)^(\s\*\s)?!slap\s+(?P<name>.+)$").unwrap(),
+            mentions,
+            // `!slap <name>`, or the body of `/me slaps <target>`
+            re: Regex::new(
+                r"(?i)^(\s\*\s)?!slap\s+(?P<name>.+)$|^slaps\s+(?P<target>.+?)(\s+around.*)?$",
+            )
+            .unwrap(),
         }
     }
 }
 
+impl TroutSlap {
+    /// Answers `/me slaps <bot>` in kind.
+    fn slap_back(&self, message: &Message) -> Option<AnyMessageEventContent> {
+        super::new_message(format!(
+            "_slaps {} around with an even larger trout_",
+            message.sender_name
+        ))
+    }
+}
+
 #[async_trait]
 impl Handler for TroutSlap {
+    fn name(&self) -> &str {
+        "slap"
+    }
+
     fn cmd(&self) -> &str {
         "!slap"
     }
@@ -28,23 +51,47 @@ impl Handler for TroutSlap {
         "a good ol' trout slapping"
     }
 
-    async fn handle(&self, sender: &str, message: &str) -> Option<AnyMessageEventContent> {
-        let captures = self.re.captures(message);
-        if captures.is_none() {
-            event!(Level::DEBUG, is_match = false);
-            return None;
+    fn accepts(&self, kind: MessageKind) -> bool {
+        matches!(kind, MessageKind::Text | MessageKind::Emote)
+    }
+
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
+
+    fn matches(&self, message: &Message) -> Option<Match> {
+        let m = Match::new(message, &self.re)?;
+        let claimed = match message.kind {
+            // slaps aimed at anyone else are none of our business
+            MessageKind::Emote => {
+                matches!(m.get("target"), Some(t) if self.mentions.refers_to_bot(t))
+            }
+            _ => m.get("name").is_some(),
+        };
+        claimed.then_some(m)
+    }
+
+    fn cooldown(&self) -> CooldownConfig {
+        CooldownConfig {
+            per_room: Some(10),
+            ..CooldownConfig::default()
+        }
+    }
+
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let message = &m.message;
+        if message.kind == MessageKind::Emote {
+            return self.slap_back(message);
         }
-        event!(Level::DEBUG, is_match = true);
 
-        if bot_mentioned(message) {
+        let slapped = m.get("name")?;
+        if self.mentions.refers_to_bot(slapped) {
             return super::new_message("EXCUSE ME I DON'T THINK SO".into());
         }
 
-        let slapped = captures.unwrap().name("name").unwrap();
         super::new_message(format!(
             "_{} slaps {} around with a large trout_",
-            sender,
-            slapped.as_str()
+            message.sender_name, slapped
         ))
     }
 }
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::Client;
+use regex::Regex;
+use tracing::{event, Level};
+
+use super::{Handler, Match};
+use crate::responses::ResponseLog;
+
+/// Redacts the bot's most recent response to whoever asks.
+#[derive(Debug)]
+pub struct Undo {
+    client: Client,
+    responses: Arc<ResponseLog>,
+    re: Regex,
+}
+
+impl Undo {
+    pub fn new(client: Client, responses: Arc<ResponseLog>) -> Self {
+        Self {
+            client,
+            responses,
+            re: Regex::new(r"(?i)^(\s\*\s)?!(undo|oops)\s*$").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Undo {
+    fn name(&self) -> &str {
+        "undo"
+    }
+
+    fn cmd(&self) -> &str {
+        "!undo"
+    }
+
+    fn description(&self) -> &str {
+        "Deletes my last response to you (or react to it with ❌)"
+    }
+
+    fn pattern(&self) -> Option<&Regex> {
+        Some(&self.re)
+    }
+
+    // otherwise the next `!undo` would take back what this one said
+    fn undoable(&self) -> bool {
+        false
+    }
+
+    async fn handle(&self, m: &Match) -> Option<AnyMessageEventContent> {
+        let message = &m.message;
+        let response = match self.responses.last_for(&message.room_id, &message.sender) {
+            Some(r) => r,
+            None => return super::new_message("I haven't said anything to you lately.".into()),
+        };
+        let room = self.client.get_joined_room(&message.room_id)?;
+
+        match room
+            .redact(&response.event_id, Some("removed by request"), None)
+            .await
+        {
+            Ok(_) => {
+                self.responses.remove(&response.room_id, &response.event_id);
+                event!(
+                    Level::INFO,
+                    "redacted response {} at the request of {}",
+                    response.event_id,
+                    message.sender
+                );
+                None
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "failed to redact response {}: {}",
+                    response.event_id,
+                    e
+                );
+                super::new_message(format!("I couldn't take that back: {}", e))
+            }
+        }
+    }
+}
+
+unsafe impl Sync for Undo {}
+unsafe impl Send for Undo {}
@@ -0,0 +1,133 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use matrix_sdk::ruma::{RoomId, UserId};
+
+use crate::handlers::{Message, MessageKind};
+use crate::middleware::Middleware;
+use crate::settings::RoomSettings;
+
+/// How many recent messages are remembered in each room.
+const CAPACITY: usize = 100;
+
+/// A message someone said recently.
+#[derive(Debug, Clone)]
+pub struct Said {
+    pub sender: UserId,
+    pub sender_name: String,
+    /// What they said, with emotes written out as `* name does something`.
+    pub text: String,
+}
+
+impl Said {
+    /// Returns whether this was said by `user`, named by user ID, with or
+    /// without the server name, or by display name.
+    fn is_by(&self, user: &str) -> bool {
+        let name = user.trim_start_matches('@');
+        self.sender.as_str() == user
+            || self.sender.localpart().eq_ignore_ascii_case(name)
+            || self.sender_name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Remembers what people said recently in rooms where a handler that needs
+/// it, such as `quote` for `!grab`, is enabled. Commands, notices and
+/// reactions aren't remembered.
+#[derive(Debug)]
+pub struct History {
+    settings: Arc<RoomSettings>,
+    /// The handler the history is kept for.
+    handler: String,
+    /// Recent messages in each room, newest last.
+    rooms: Mutex<HashMap<RoomId, VecDeque<Said>>>,
+}
+
+impl History {
+    pub(crate) fn new(settings: Arc<RoomSettings>, handler: &str) -> Self {
+        Self {
+            settings,
+            handler: handler.into(),
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn remember(&self, message: &Message) {
+        let said = matches!(message.kind, MessageKind::Text | MessageKind::Emote);
+        if !said || message.body.starts_with('!') {
+            return;
+        }
+        let text = match message.kind {
+            MessageKind::Emote => format!("* {} {}", message.sender_name, message.body),
+            _ => message.body.clone(),
+        };
+
+        let mut rooms = self.rooms.lock().unwrap();
+        let said = rooms.entry(message.room_id.clone()).or_default();
+        said.push_back(Said {
+            sender: message.sender.clone(),
+            sender_name: message.sender_name.clone(),
+            text,
+        });
+        if said.len() > CAPACITY {
+            said.pop_front();
+        }
+    }
+
+    /// Returns the last thing `user` said in `room_id`. Users can be named
+    /// by user ID, with or without the server name, or by display name.
+    pub fn last_said(&self, room_id: &RoomId, user: &str) -> Option<Said> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .get(room_id)?
+            .iter()
+            .rev()
+            .find(|s| s.is_by(user))
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl Middleware for History {
+    fn name(&self) -> &str {
+        "history"
+    }
+
+    async fn before(&self, message: Message) -> Option<Message> {
+        if self
+            .settings
+            .handler_enabled(&message.room_id, &self.handler)
+        {
+            self.remember(&message);
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn said(sender: &str, sender_name: &str) -> Said {
+        Said {
+            sender: UserId::try_from(sender).unwrap(),
+            sender_name: sender_name.into(),
+            text: "hello".into(),
+        }
+    }
+
+    #[test]
+    fn finds_who_said_it() {
+        let alice = said("@alice:example.org", "Alice Liddell");
+        assert!(alice.is_by("@alice:example.org"));
+        assert!(alice.is_by("@alice"));
+        assert!(alice.is_by("ALICE"));
+        assert!(alice.is_by("alice liddell"));
+
+        assert!(!alice.is_by("@alice:example.com"));
+        assert!(!alice.is_by("bob"));
+        assert!(!alice.is_by("Alice L"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Client;
+use regex::Regex;
+use tracing::{event, Level};
+
+use crate::config::IgnoreConfig;
+use crate::errors::*;
+use crate::handlers::{Message, MessageKind};
+use crate::middleware::Middleware;
+use crate::store;
+
+/// Custom store key holding the users seen sending notices.
+const NOTICE_SENDERS_KEY: &[u8] = b"bingo.ignore.notice_senders";
+
+/// Custom store key holding the users an admin has stopped ignoring, who
+/// aren't ignored for sending notices again.
+const EXEMPT_KEY: &[u8] = b"bingo.ignore.exempt";
+
+/// Decides whose messages the bot ignores: configured users, users matching
+/// a configured pattern, and anyone who has sent a notice.
+#[derive(Debug)]
+pub struct IgnoreList {
+    client: Client,
+    users: HashSet<String>,
+    patterns: Vec<Regex>,
+    notice_senders: bool,
+    /// Users seen sending notices, remembered across restarts.
+    noticed: RwLock<HashSet<UserId>>,
+    /// Users an admin has stopped ignoring.
+    exempt: RwLock<HashSet<UserId>>,
+}
+
+impl IgnoreList {
+    pub(crate) fn new(client: Client, config: &IgnoreConfig) -> Result<Self> {
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|p| {
+                Regex::new(&format!("^(?:{})$", p))
+                    .map_err(|e| Error::BotError(format!("invalid ignore pattern {}: {}", p, e)))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            client,
+            users: config.users.iter().cloned().collect(),
+            patterns,
+            notice_senders: config.notice_senders,
+            noticed: RwLock::new(HashSet::new()),
+            exempt: RwLock::new(HashSet::new()),
+        })
+    }
+
+    /// Reads the notice senders seen so far, and the users exempted from
+    /// being ignored for it, from the state store.
+    pub(crate) async fn load(&self) -> Result<()> {
+        let noticed = store::load(&self.client, NOTICE_SENDERS_KEY).await?;
+        let exempt = store::load(&self.client, EXEMPT_KEY).await?;
+        *self.noticed.write().unwrap() = noticed;
+        *self.exempt.write().unwrap() = exempt;
+        Ok(())
+    }
+
+    /// Returns whether the account's configuration ignores `user_id`, which
+    /// can only be undone by changing it.
+    pub(crate) fn is_configured(&self, user_id: &UserId) -> bool {
+        self.users.contains(user_id.as_str())
+            || self.patterns.iter().any(|p| p.is_match(user_id.as_str()))
+    }
+
+    pub(crate) fn is_ignored(&self, user_id: &UserId) -> bool {
+        self.is_configured(user_id) || self.noticed.read().unwrap().contains(user_id)
+    }
+
+    /// Notes that `user_id` sent a notice, ignoring them from now on if the
+    /// account is configured to and an admin hasn't exempted them.
+    pub(crate) async fn saw_notice(&self, user_id: &UserId) -> Result<()> {
+        if !self.notice_senders || self.exempt.read().unwrap().contains(user_id) {
+            return Ok(());
+        }
+
+        let noticed = {
+            let mut noticed = self.noticed.write().unwrap();
+            if !noticed.insert(user_id.clone()) {
+                return Ok(());
+            }
+            noticed.clone()
+        };
+        event!(Level::INFO, "ignoring {}, who sent a notice", user_id);
+        store::save(&self.client, NOTICE_SENDERS_KEY, &noticed).await
+    }
+
+    /// Stops ignoring `user_id` for having sent a notice, for good. Returns
+    /// false if they weren't ignored for that.
+    pub(crate) async fn unignore(&self, user_id: &UserId) -> Result<bool> {
+        let (noticed, exempt) = {
+            let mut noticed = self.noticed.write().unwrap();
+            if !noticed.remove(user_id) {
+                return Ok(false);
+            }
+            let mut exempt = self.exempt.write().unwrap();
+            exempt.insert(user_id.clone());
+            (noticed.clone(), exempt.clone())
+        };
+        event!(Level::INFO, "no longer ignoring {}", user_id);
+        store::save(&self.client, NOTICE_SENDERS_KEY, &noticed).await?;
+        store::save(&self.client, EXEMPT_KEY, &exempt).await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Middleware for IgnoreList {
+    fn name(&self) -> &str {
+        "ignore"
+    }
+
+    async fn before(&self, message: Message) -> Option<Message> {
+        if message.kind == MessageKind::Notice {
+            if let Err(e) = self.saw_notice(&message.sender).await {
+                event!(Level::WARN, "failed to save ignored users: {}", e);
+            }
+        }
+        if self.is_ignored(&message.sender) {
+            event!(Level::DEBUG, "ignoring message from {}", message.sender);
+            return None;
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use url::Url;
+
+    use super::*;
+
+    fn ignore_list(config: serde_json::Value) -> IgnoreList {
+        let config = serde_json::from_value(config).unwrap();
+        let client = Client::new(Url::parse("https://example.org").unwrap()).unwrap();
+        IgnoreList::new(client, &config).unwrap()
+    }
+
+    fn user(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn ignores_configured_users() {
+        let list = ignore_list(serde_json::json!({
+            "users": ["@spam:example.org"],
+            "patterns": [r"@.*bot:example\.org"],
+        }));
+
+        assert!(list.is_ignored(&user("@spam:example.org")));
+        assert!(list.is_ignored(&user("@github-bot:example.org")));
+        assert!(!list.is_ignored(&user("@alice:example.org")));
+        // patterns match the whole user ID
+        assert!(!list.is_ignored(&user("@github-bot:example.org.evil")));
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        let config = serde_json::from_value::<IgnoreConfig>(serde_json::json!({
+            "patterns": ["@(unclosed"],
+        }));
+        assert!(config.is_err());
+
+        let client = Client::new(Url::parse("https://example.org").unwrap()).unwrap();
+        let config = IgnoreConfig {
+            patterns: vec!["@(unclosed".into()],
+            ..Default::default()
+        };
+        assert!(IgnoreList::new(client, &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn unignores_notice_senders() {
+        let list = ignore_list(serde_json::json!({ "users": ["@spam:example.org"] }));
+        let bot = user("@bot:example.org");
+
+        list.saw_notice(&bot).await.unwrap();
+        assert!(list.is_ignored(&bot));
+
+        assert!(list.unignore(&bot).await.unwrap());
+        assert!(!list.is_ignored(&bot));
+        assert!(!list.unignore(&bot).await.unwrap());
+
+        // once an admin has vouched for them, notices don't count
+        list.saw_notice(&bot).await.unwrap();
+        assert!(!list.is_ignored(&bot));
+
+        let spam = user("@spam:example.org");
+        assert!(!list.unignore(&spam).await.unwrap());
+        assert!(list.is_ignored(&spam));
+    }
+
+    #[tokio::test]
+    async fn can_leave_notice_senders_alone() {
+        let list = ignore_list(serde_json::json!({ "notice_senders": false }));
+        let bot = user("@bot:example.org");
+
+        list.saw_notice(&bot).await.unwrap();
+        assert!(!list.is_ignored(&bot));
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use matrix_sdk::ruma::api::client::r0::membership::leave_room;
+use matrix_sdk::ruma::api::client::r0::room::create_room;
+use matrix_sdk::ruma::events::room::message::{
+    MessageEventContent, MessageType, NoticeMessageEventContent,
+};
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::{RoomId, UserId};
+use matrix_sdk::Client;
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+use crate::config::{AccountConfig, InviteConfig};
+use crate::errors::*;
+use crate::store;
+
+/// Custom store key holding the invites awaiting an admin's approval.
+const PENDING_KEY: &[u8] = b"bingo.invites.pending";
+
+/// Prefix of the custom store keys holding the direct message room shared
+/// with each admin.
+const ADMIN_DM_PREFIX: &str = "bingo.admin_dm.";
+
+/// What to do with an invite.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Accept,
+    Ask(String),
+    Reject(String),
+}
+
+/// What became of an admin's approval of an invite.
+#[derive(Debug, PartialEq)]
+pub enum Approval {
+    /// The bot joined the room.
+    Joined,
+    /// There was no pending invite to the room.
+    NotPending,
+    /// The bot may not join another room yet, for the reason given. The
+    /// invite is still pending.
+    Refused(String),
+}
+
+/// Returns why the bot may not join another room while it's joined to
+/// `joined` rooms, if it may not.
+fn over_cap(policy: &InviteConfig, joined: usize) -> Option<String> {
+    match policy.max_rooms {
+        Some(max) if joined >= max => {
+            Some(format!("already joined to {} of {} rooms", joined, max))
+        }
+        _ => None,
+    }
+}
+
+/// Applies `policy` to an invite from `inviter` while the bot is joined to
+/// `joined` rooms. Admins' invites are accepted unless the bot is already in
+/// as many rooms as it may be.
+pub(crate) fn verdict(
+    policy: &InviteConfig,
+    admins: &[String],
+    inviter: &UserId,
+    joined: usize,
+) -> Verdict {
+    if let Some(reason) = over_cap(policy, joined) {
+        return Verdict::Reject(reason);
+    }
+
+    if admins.iter().any(|a| a == inviter.as_str()) {
+        return Verdict::Accept;
+    }
+
+    let unrestricted = policy.allowed_users.is_empty() && policy.allowed_servers.is_empty();
+    let allowed = policy.allowed_users.iter().any(|u| u == inviter.as_str())
+        || policy
+            .allowed_servers
+            .iter()
+            .any(|s| s == inviter.server_name().as_str());
+
+    if allowed || (unrestricted && !policy.require_approval) {
+        Verdict::Accept
+    } else if policy.require_approval {
+        Verdict::Ask(if unrestricted {
+            "all invites need approval".into()
+        } else {
+            format!("{} is not on the allowlist", inviter)
+        })
+    } else {
+        Verdict::Reject(format!("{} is not on the allowlist", inviter))
+    }
+}
+
+/// Applies an account's invite policy, keeping track of the invites waiting
+/// for an admin to approve or reject them.
+#[derive(Debug)]
+pub struct Invites {
+    client: Client,
+    account: Arc<AccountConfig>,
+    lock: Mutex<()>,
+}
+
+impl Invites {
+    pub(crate) fn new(client: Client, account: Arc<AccountConfig>) -> Self {
+        Self {
+            client,
+            account,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn admins(&self) -> &[String] {
+        &self.account.admins
+    }
+
+    /// Decides what to do with an invite to `room_id` from `inviter`,
+    /// rejecting it or asking the admins about it as the policy requires.
+    /// Returns whether the invite should be accepted now.
+    pub(crate) async fn review(&self, room_id: &RoomId, inviter: &UserId) -> bool {
+        let joined = self.client.joined_rooms().len();
+        match verdict(&self.account.invites, &self.account.admins, inviter, joined) {
+            Verdict::Accept => true,
+            Verdict::Reject(reason) => {
+                event!(
+                    Level::INFO,
+                    "rejecting invite to {} from {}: {}",
+                    room_id,
+                    inviter,
+                    reason
+                );
+                if let Err(e) = self.leave(room_id).await {
+                    event!(Level::WARN, "failed to reject invite to {}: {}", room_id, e);
+                }
+                false
+            }
+            Verdict::Ask(reason) => {
+                event!(
+                    Level::INFO,
+                    "holding invite to {} from {} for approval: {}",
+                    room_id,
+                    inviter,
+                    reason
+                );
+                if let Err(e) = self.ask_admins(room_id, inviter).await {
+                    event!(
+                        Level::WARN,
+                        "failed to ask for approval of invite to {}: {}",
+                        room_id,
+                        e
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    /// Returns the invites awaiting approval, mapping room IDs to inviters.
+    pub async fn pending(&self) -> Result<BTreeMap<RoomId, UserId>> {
+        store::load(&self.client, PENDING_KEY).await
+    }
+
+    async fn set_pending(&self, pending: &BTreeMap<RoomId, UserId>) -> Result<()> {
+        store::save(&self.client, PENDING_KEY, pending).await
+    }
+
+    /// Removes the invite to `room_id` from the pending list, returning
+    /// whether it was there.
+    async fn take_pending(&self, room_id: &RoomId) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let mut pending = self.pending().await?;
+        let found = pending.remove(room_id).is_some();
+        if found {
+            self.set_pending(&pending).await?;
+        }
+        Ok(found)
+    }
+
+    /// Accepts a pending invite, unless the bot is already in as many rooms
+    /// as it may be.
+    pub async fn approve(&self, room_id: &RoomId) -> Result<Approval> {
+        if !self.pending().await?.contains_key(room_id) {
+            return Ok(Approval::NotPending);
+        }
+        let joined = self.client.joined_rooms().len();
+        if let Some(reason) = over_cap(&self.account.invites, joined) {
+            event!(
+                Level::INFO,
+                "not joining room {} after approval: {}",
+                room_id,
+                reason
+            );
+            return Ok(Approval::Refused(reason));
+        }
+
+        if !self.take_pending(room_id).await? {
+            return Ok(Approval::NotPending);
+        }
+        self.client.join_room_by_id(room_id).await?;
+        event!(Level::INFO, "joined room {} after approval", room_id);
+        Ok(Approval::Joined)
+    }
+
+    /// Rejects a pending invite. Returns false if there was no such invite.
+    pub async fn reject(&self, room_id: &RoomId) -> Result<bool> {
+        if !self.take_pending(room_id).await? {
+            return Ok(false);
+        }
+        self.leave(room_id).await?;
+        event!(Level::INFO, "rejected invite to {} by request", room_id);
+        Ok(true)
+    }
+
+    async fn leave(&self, room_id: &RoomId) -> Result<()> {
+        self.client
+            .send(leave_room::Request::new(room_id), None)
+            .await?;
+        Ok(())
+    }
+
+    /// Records the invite as pending and tells each admin about it, unless
+    /// they've already been told.
+    async fn ask_admins(&self, room_id: &RoomId, inviter: &UserId) -> Result<()> {
+        {
+            let _guard = self.lock.lock().await;
+            let mut pending = self.pending().await?;
+            if pending.contains_key(room_id) {
+                return Ok(());
+            }
+            pending.insert(room_id.clone(), inviter.clone());
+            self.set_pending(&pending).await?;
+        }
+
+        if self.account.admins.is_empty() {
+            event!(
+                Level::WARN,
+                "invite to {} needs approval, but no admins are configured",
+                room_id
+            );
+            return Ok(());
+        }
+
+        let notice = format!(
+            "{} invited me to {}. Reply with `!approve {}` or `!reject {}`.",
+            inviter, room_id, room_id, room_id
+        );
+        for admin in &self.account.admins {
+            let admin = UserId::try_from(admin.as_str())?;
+            let dm = self.admin_dm(&admin).await?;
+            self.client
+                .room_send(
+                    &dm,
+                    AnyMessageEventContent::RoomMessage(MessageEventContent::new(
+                        MessageType::Notice(NoticeMessageEventContent::markdown(notice.clone())),
+                    )),
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the direct message room shared with `admin`, creating it if
+    /// there isn't one yet.
+    async fn admin_dm(&self, admin: &UserId) -> Result<RoomId> {
+        let key = format!("{}{}", ADMIN_DM_PREFIX, admin);
+        let store = self.client.store();
+        if let Some(v) = store.get_custom_value(key.as_bytes()).await? {
+            let room_id = RoomId::try_from(String::from_utf8_lossy(&v).as_ref())?;
+            if self.client.get_left_room(&room_id).is_none() {
+                return Ok(room_id);
+            }
+        }
+
+        let invite = [admin.clone()];
+        let mut request = create_room::Request::new();
+        request.invite = &invite;
+        request.is_direct = true;
+        request.preset = Some(create_room::RoomPreset::TrustedPrivateChat);
+        let room_id = self.client.create_room(request).await?.room_id;
+
+        store
+            .set_custom_value(key.as_bytes(), room_id.as_str().as_bytes().to_vec())
+            .await?;
+        event!(Level::INFO, "created direct message room with {}", admin);
+        Ok(room_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    fn policy(users: &[&str], servers: &[&str], approval: bool) -> InviteConfig {
+        InviteConfig {
+            allowed_users: users.iter().map(|u| u.to_string()).collect(),
+            allowed_servers: servers.iter().map(|s| s.to_string()).collect(),
+            max_rooms: None,
+            require_approval: approval,
+        }
+    }
+
+    const ADMINS: &[String] = &[];
+
+    #[test]
+    fn accepts_anyone_without_an_allowlist() {
+        let open = policy(&[], &[], false);
+        assert_eq!(
+            verdict(&open, ADMINS, &user("@anyone:example.com"), 0),
+            Verdict::Accept
+        );
+
+        let approval = policy(&[], &[], true);
+        assert_eq!(
+            verdict(&approval, ADMINS, &user("@anyone:example.com"), 0),
+            Verdict::Ask("all invites need approval".into())
+        );
+    }
+
+    #[test]
+    fn follows_the_allowlist() {
+        let strict = policy(&["@alice:example.com"], &["example.org"], false);
+        assert_eq!(
+            verdict(&strict, ADMINS, &user("@alice:example.com"), 0),
+            Verdict::Accept
+        );
+        assert_eq!(
+            verdict(&strict, ADMINS, &user("@bob:example.org"), 0),
+            Verdict::Accept
+        );
+        assert_eq!(
+            verdict(&strict, ADMINS, &user("@eve:example.com"), 0),
+            Verdict::Reject("@eve:example.com is not on the allowlist".into())
+        );
+        assert_eq!(
+            verdict(&strict, ADMINS, &user("@eve:example.org.evil"), 0),
+            Verdict::Reject("@eve:example.org.evil is not on the allowlist".into())
+        );
+
+        let approval = policy(&["@alice:example.com"], &[], true);
+        assert_eq!(
+            verdict(&approval, ADMINS, &user("@alice:example.com"), 0),
+            Verdict::Accept
+        );
+        assert_eq!(
+            verdict(&approval, ADMINS, &user("@eve:example.com"), 0),
+            Verdict::Ask("@eve:example.com is not on the allowlist".into())
+        );
+    }
+
+    #[test]
+    fn admins_are_capped_too() {
+        let admins = vec!["@admin:example.com".to_string()];
+        let capped = InviteConfig {
+            max_rooms: Some(2),
+            ..policy(&[], &[], true)
+        };
+        let admin = user("@admin:example.com");
+
+        assert_eq!(verdict(&capped, &admins, &admin, 1), Verdict::Accept);
+        assert_eq!(
+            verdict(&capped, &admins, &admin, 2),
+            Verdict::Reject("already joined to 2 of 2 rooms".into())
+        );
+        assert_eq!(
+            verdict(&capped, &admins, &user("@alice:example.com"), 1),
+            Verdict::Ask("all invites need approval".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn approvals_are_capped() {
+        let client = Client::new(url::Url::parse("https://example.org").unwrap()).unwrap();
+        let account: AccountConfig = serde_json::from_value(serde_json::json!({
+            "homeserver": "https://example.org",
+            "invites": { "max_rooms": 0 },
+        }))
+        .unwrap();
+        let invites = Invites::new(client, Arc::new(account));
+        let room_id = RoomId::try_from("!room:example.org").unwrap();
+
+        let mut pending = BTreeMap::new();
+        pending.insert(room_id.clone(), user("@alice:example.com"));
+        invites.set_pending(&pending).await.unwrap();
+
+        assert_eq!(
+            invites.approve(&room_id).await.unwrap(),
+            Approval::Refused("already joined to 0 of 0 rooms".into())
+        );
+        assert!(invites.pending().await.unwrap().contains_key(&room_id));
+
+        let other = RoomId::try_from("!other:example.org").unwrap();
+        assert_eq!(invites.approve(&other).await.unwrap(), Approval::NotPending);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+use tracing::{event, Level};
+
+use crate::errors::*;
+use crate::store;
+
+/// Custom store key holding the joins waiting to be retried.
+const QUEUE_KEY: &[u8] = b"bingo.joins.pending";
+
+/// How often the queue is checked for joins that are due.
+const POLL_INTERVAL: u64 = 30;
+
+/// The shortest and longest waits between attempts to join a room, in
+/// seconds.
+const MIN_RETRY_DELAY: u64 = 30;
+const MAX_RETRY_DELAY: u64 = 3600;
+
+/// How many times to try joining a room before giving up on it.
+const MAX_ATTEMPTS: u32 = 24;
+
+/// A room the bot failed to join and will try again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingJoin {
+    pub attempts: u32,
+    /// When to try next, in seconds since the Unix epoch.
+    pub next_attempt: u64,
+    pub last_error: String,
+}
+
+/// Joins that failed, kept in the state store so that they're retried
+/// across restarts.
+#[derive(Debug)]
+pub struct JoinQueue {
+    client: Client,
+    lock: Mutex<()>,
+}
+
+impl JoinQueue {
+    pub(crate) fn new(client: Client) -> Self {
+        Self {
+            client,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the joins waiting to be retried.
+    pub async fn pending(&self) -> Result<BTreeMap<RoomId, PendingJoin>> {
+        store::load(&self.client, QUEUE_KEY).await
+    }
+
+    /// Queues `room_id` to be retried after a failed first attempt.
+    pub(crate) async fn add(&self, room_id: &RoomId, error: &Error) {
+        let _guard = self.lock.lock().await;
+        let result = async {
+            let mut pending = self.pending().await?;
+            let entry = pending.entry(room_id.clone()).or_insert(PendingJoin {
+                attempts: 1,
+                next_attempt: 0,
+                last_error: String::new(),
+            });
+            let delay = retry_delay(entry.attempts);
+            entry.next_attempt = now() + delay;
+            entry.last_error = error.to_string();
+            store::save(&self.client, QUEUE_KEY, &pending).await?;
+            Ok::<_, Error>(delay)
+        }
+        .await;
+
+        match result {
+            Ok(delay) => event!(
+                Level::WARN,
+                "failed to join room {} ({}), will retry in {}s",
+                room_id,
+                error,
+                delay
+            ),
+            Err(e) => event!(
+                Level::ERROR,
+                "failed to join room {} ({}) and couldn't queue a retry: {}",
+                room_id,
+                error,
+                e
+            ),
+        }
+    }
+
+    /// Stops retrying `room_id`. Returns false if it wasn't queued.
+    pub async fn drop_join(&self, room_id: &RoomId) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let mut pending = self.pending().await?;
+        let found = pending.remove(room_id).is_some();
+        if found {
+            store::save(&self.client, QUEUE_KEY, &pending).await?;
+            event!(Level::INFO, "dropped pending join to room {}", room_id);
+        }
+        Ok(found)
+    }
+
+    /// Retries queued joins as they come due. This never returns.
+    pub(crate) async fn run(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.retry_due().await {
+                event!(Level::WARN, "failed to process pending joins: {}", e);
+            }
+            sleep(Duration::from_secs(POLL_INTERVAL)).await;
+        }
+    }
+
+    async fn retry_due(&self) -> Result<()> {
+        let due: Vec<RoomId> = self
+            .pending()
+            .await?
+            .into_iter()
+            .filter(|(_, j)| j.next_attempt <= now())
+            .map(|(room_id, _)| room_id)
+            .collect();
+
+        for room_id in due {
+            let result = self.client.join_room_by_id(&room_id).await;
+
+            let _guard = self.lock.lock().await;
+            let mut pending = self.pending().await?;
+            let join = match pending.get_mut(&room_id) {
+                Some(j) => j,
+                // dropped while we were trying
+                None => continue,
+            };
+            join.attempts += 1;
+
+            match result {
+                Ok(_) => {
+                    event!(
+                        Level::INFO,
+                        "joined room {} after {} attempts",
+                        room_id,
+                        join.attempts
+                    );
+                    pending.remove(&room_id);
+                }
+                Err(e) if join.attempts >= MAX_ATTEMPTS => {
+                    event!(
+                        Level::ERROR,
+                        "giving up on joining room {} after {} attempts: {}",
+                        room_id,
+                        join.attempts,
+                        e
+                    );
+                    pending.remove(&room_id);
+                }
+                Err(e) => {
+                    let delay = retry_delay(join.attempts);
+                    event!(
+                        Level::WARN,
+                        "attempt {} to join room {} failed ({}), retrying in {}s",
+                        join.attempts,
+                        room_id,
+                        e,
+                        delay
+                    );
+                    join.next_attempt = now() + delay;
+                    join.last_error = e.to_string();
+                }
+            }
+            store::save(&self.client, QUEUE_KEY, &pending).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The wait after the given number of failed attempts, doubling each time.
+fn retry_delay(attempts: u32) -> u64 {
+    MIN_RETRY_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -1,50 +1,264 @@
 use std::collections::HashMap;
-use std::path::Path;
+use std::convert::TryFrom;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use matrix_sdk::{
-    room::Room,
+    event_handler::RawEvent,
+    room::{Joined, Room},
     ruma::events::{
+        reaction::ReactionEventContent,
         room::{
-            member::MemberEventContent,
-            message::{MessageEventContent, MessageType, TextMessageEventContent},
+            member::{MemberEventContent, MembershipState},
+            message::{MessageEventContent, MessageType, Relation},
+            redaction::{RedactionEventContent, SyncRedactionEvent},
+            tombstone::TombstoneEventContent,
         },
-        StrippedStateEvent, SyncMessageEvent,
+        space::child::ChildEventContent,
+        AnyMessageEventContent, AnyStateEventContent, StrippedStateEvent, SyncMessageEvent,
+        SyncStateEvent,
+    },
+    ruma::{
+        api::client::r0::presence::set_presence, presence::PresenceState, EventId, RoomId, UserId,
     },
     Client, ClientConfig, SyncSettings,
 };
-
-use tokio::time::{sleep, Duration};
+use sha2::{Digest, Sha256};
+use tokio::time::{sleep, timeout, Duration};
 use tracing::{event, Level};
 use url::Url;
 
 pub(crate) mod errors;
 pub use errors::*;
 
+#[cfg(feature = "appservice")]
+pub mod appservice;
+mod breaker;
+pub mod config;
+mod cooldowns;
+mod empty_rooms;
+pub mod feedback;
 pub mod handlers;
+pub mod history;
+pub mod ignore;
+pub mod invites;
+pub mod joins;
+pub mod mentions;
+pub mod middleware;
+mod permissions;
+pub mod responses;
+pub mod scheduler;
+mod settings;
+pub mod shutdown;
+mod spaces;
+mod store;
+pub mod tasks;
+mod upgrades;
+mod welcome;
+
+use breaker::CircuitBreaker;
+use config::{AccountConfig, TimeoutConfig};
+use cooldowns::Cooldowns;
+use empty_rooms::EmptyRooms;
+use feedback::Feedback;
+use handlers::karma::KarmaTracker;
+use handlers::{BotState, Context, Message, MessageKind, Outcome, Reaction, Registry};
+use history::History;
+use ignore::IgnoreList;
+use invites::Invites;
+use joins::JoinQueue;
+use mentions::{MentionDetector, RawMessage};
+use middleware::{Middleware, Pipeline, Verdict};
+use permissions::Permissions;
+use responses::{Response, ResponseLog};
+use scheduler::Scheduler;
+use settings::RoomSettings;
+use shutdown::{InFlight, Shutdown, Task};
+use spaces::Spaces;
+use tasks::Tasks;
+use upgrades::RoomUpgrades;
+use welcome::Welcomer;
+
+/// The power level from which room members count as moderators.
+const MODERATOR_POWER_LEVEL: i64 = 50;
+
+/// Custom store key holding the hash of the last avatar image uploaded.
+const AVATAR_KEY: &[u8] = b"bingo.avatar_sha256";
 
-static DISPLAY_NAME: &str = "Bingo";
+/// Custom store key holding when the bot last shut down cleanly.
+const LAST_SHUTDOWN_KEY: &[u8] = b"bingo.last_shutdown";
 
 #[derive(Debug)]
 pub struct BingoBot {
     client: Client,
     config: Option<HashMap<String, String>>,
+    account: Arc<AccountConfig>,
+    invites: Arc<Invites>,
+    empty_rooms: Arc<EmptyRooms>,
+    joins: Arc<JoinQueue>,
+    upgrades: Arc<RoomUpgrades>,
+    spaces: Arc<Spaces>,
+    settings: Arc<RoomSettings>,
+    welcomer: Arc<Welcomer>,
+    responses: Arc<ResponseLog>,
+    feedback: Arc<Feedback>,
+    ignore: Arc<IgnoreList>,
+    /// Middleware added through the builder, run after the bot's own.
+    middleware: Pipeline,
+    shutdown: Shutdown,
+    in_flight: Arc<InFlight>,
+    /// The handlers, once event handlers have been registered.
+    handlers: Option<Arc<Registry>>,
+    tasks: Tasks,
+    scheduler: Arc<Scheduler>,
+    history: Arc<History>,
+    karma: Arc<KarmaTracker>,
+}
+
+impl Drop for BingoBot {
+    /// Cancels the background tasks, in case the bot is dropped without
+    /// stopping cleanly, such as when the task running it panics. Otherwise
+    /// they'd keep running alongside those of the bot that replaces it.
+    fn drop(&mut self) {
+        self.tasks.stop();
+    }
+}
+
+/// Everything needed to dispatch messages and reactions to handlers.
+#[derive(Debug)]
+struct Dispatch {
+    handlers: Arc<Registry>,
+    settings: Arc<RoomSettings>,
+    responses: Arc<ResponseLog>,
+    feedback: Arc<Feedback>,
+    breaker: Arc<CircuitBreaker>,
+    timeouts: TimeoutConfig,
+    pipeline: Pipeline,
+    in_flight: Arc<InFlight>,
+}
+
+/// Builds a [`BingoBot`] for an account.
+#[derive(Debug)]
+pub struct BingoBotBuilder {
+    account: AccountConfig,
+    store_path: PathBuf,
+    config: Option<HashMap<String, String>>,
+    middleware: Pipeline,
+    shutdown: Option<Shutdown>,
+}
+
+impl BingoBotBuilder {
+    pub fn new(account: &AccountConfig, store_path: &Path) -> Self {
+        Self {
+            account: account.clone(),
+            store_path: store_path.to_path_buf(),
+            config: None,
+            middleware: Pipeline::new(),
+            shutdown: None,
+        }
+    }
+
+    /// Sets the handlers' configuration, such as API keys.
+    pub fn handler_config(mut self, config: HashMap<String, String>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Adds a middleware layer. Layers run in the order they're added,
+    /// after the bot's own ignore list, circuit breaker, permissions and
+    /// cooldowns.
+    pub fn middleware(mut self, layer: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(layer));
+        self
+    }
+
+    /// Stops the bot when `shutdown` is triggered. Without one, the bot
+    /// syncs until the process exits.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn build(self) -> Result<BingoBot> {
+        let homeserver = Url::parse(&self.account.homeserver)?;
+
+        let sp = self.store_path.to_string_lossy().to_string();
+        let client_config = ClientConfig::new().store_path(&sp);
+        event!(Level::DEBUG, "store path: {}", &sp);
+
+        let client = Client::new_with_config(homeserver, client_config)?;
+
+        let mut bot = BingoBot::from_client(client, &self.account, self.config)?;
+        bot.middleware = self.middleware;
+        if let Some(shutdown) = self.shutdown {
+            bot.shutdown = shutdown;
+        }
+        Ok(bot)
+    }
 }
 
 impl BingoBot {
     pub fn new(
-        homeserver: &str,
+        account: &AccountConfig,
         store_path: &Path,
         config: Option<HashMap<String, String>>,
     ) -> Result<Self> {
-        let homeserver = Url::parse(homeserver)?;
-
-        let sp = store_path.to_string_lossy().to_string();
-        let client_config = ClientConfig::new().store_path(&sp);
-        event!(Level::DEBUG, "store path: {}", &sp);
+        let mut builder = BingoBotBuilder::new(account, store_path);
+        if let Some(config) = config {
+            builder = builder.handler_config(config);
+        }
+        builder.build()
+    }
 
-        let client = Client::new_with_config(homeserver, client_config)?;
+    pub fn builder(account: &AccountConfig, store_path: &Path) -> BingoBotBuilder {
+        BingoBotBuilder::new(account, store_path)
+    }
 
-        Ok(Self { client, config })
+    pub(crate) fn from_client(
+        client: Client,
+        account: &AccountConfig,
+        config: Option<HashMap<String, String>>,
+    ) -> Result<Self> {
+        let timezone = match &account.timezone {
+            Some(tz) => scheduler::parse_timezone(tz)?,
+            None => chrono_tz::UTC,
+        };
+        let account = Arc::new(account.clone());
+        let upgrades = Arc::new(RoomUpgrades::new(client.clone()));
+        let spaces = Arc::new(Spaces::new(client.clone(), account.clone()));
+        let settings = Arc::new(RoomSettings::new(
+            account.clone(),
+            upgrades.clone(),
+            spaces.clone(),
+        ));
+        Ok(Self {
+            invites: Arc::new(Invites::new(client.clone(), account.clone())),
+            joins: Arc::new(JoinQueue::new(client.clone())),
+            welcomer: Arc::new(Welcomer::new(client.clone(), settings.clone())),
+            responses: Arc::new(ResponseLog::default()),
+            feedback: Arc::new(Feedback::new(client.clone())),
+            ignore: Arc::new(IgnoreList::new(client.clone(), &account.ignore)?),
+            middleware: Pipeline::new(),
+            shutdown: Shutdown::new(),
+            in_flight: Arc::new(InFlight::new()),
+            handlers: None,
+            tasks: Tasks::new(),
+            scheduler: Arc::new(Scheduler::new(client.clone(), timezone)),
+            history: Arc::new(History::new(settings.clone(), handlers::quote::NAME)),
+            karma: Arc::new(KarmaTracker::new(client.clone(), settings.clone())),
+            settings,
+            upgrades,
+            spaces,
+            empty_rooms: Arc::new(EmptyRooms::new(
+                client.clone(),
+                Duration::from_secs(account.empty_room_grace),
+            )),
+            client,
+            config,
+            account,
+        })
     }
 
     pub async fn login_and_sync(&mut self, username: &str, password: &str) -> Result<()> {
@@ -55,12 +269,14 @@ impl BingoBot {
     pub async fn login(&mut self, username: &str, password: &str) -> Result<()> {
         event!(Level::DEBUG, "attempting to log in as {}", username);
         self.client
-            .login(username, password, None, Some(DISPLAY_NAME))
+            .login(username, password, None, Some(self.account.display_name()))
             .await?;
         event!(Level::INFO, "successfully logged in as {}", username);
 
-        // XXX: is this needed?
-        self.client.set_display_name(Some(DISPLAY_NAME)).await?;
+        self.apply_identity().await?;
+
+        // pending invites arrive with the initial sync
+        self.register_invite_handler().await;
 
         // throw away old messages
         event!(Level::DEBUG, "performing initial sync");
@@ -91,7 +307,7 @@ impl BingoBot {
                 event!(
                     Level::INFO,
                     "leaving rooms where {} is the only member",
-                    DISPLAY_NAME
+                    self.account.display_name()
                 );
                 for room in empty_rooms {
                     if room
@@ -111,54 +327,381 @@ impl BingoBot {
             self.client.sync_once(SyncSettings::default()).await?;
         }
 
-        let invited = self.client.invited_rooms();
-        if !invited.is_empty() {
-            event!(Level::INFO, "joining rooms to which we were invited");
-            for room in invited {
-                let display_name = match room.display_name().await {
-                    Ok(name) => format!(" ({})", name),
-                    Err(_) => String::from(""),
-                };
+        self.follow_upgrades().await;
+        self.spaces.join_all(&self.joins).await;
+        self.apply_room_display_names().await;
+        self.register_event_handlers().await;
+        self.start_handlers().await;
 
+        Ok(())
+    }
+
+    /// Sets the bot's display name and, if one is configured, its avatar.
+    pub(crate) async fn apply_identity(&self) -> Result<()> {
+        self.client
+            .set_display_name(Some(self.account.display_name()))
+            .await?;
+
+        if let Some(path) = &self.account.avatar {
+            if let Err(e) = self.update_avatar(path).await {
                 event!(
-                    Level::INFO,
-                    "joining room {}{}",
-                    room.room_id().as_str(),
-                    display_name
+                    Level::WARN,
+                    "failed to set avatar from {}: {}",
+                    path.display(),
+                    e
                 );
-                match room.accept_invitation().await {
-                    Ok(_) => {}
-                    Err(e) => event!(Level::ERROR, "failed to join room: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn update_avatar(&self, path: &Path) -> Result<()> {
+        let image = std::fs::read(path)?;
+        let digest = Sha256::digest(&image).to_vec();
+
+        let store = self.client.store();
+        if store.get_custom_value(AVATAR_KEY).await?.as_deref() == Some(&digest[..])
+            && self.client.avatar_url().await?.is_some()
+        {
+            event!(Level::DEBUG, "avatar is up to date");
+            return Ok(());
+        }
+
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let content_type = match extension.as_str() {
+            "gif" => mime::IMAGE_GIF,
+            "jpg" | "jpeg" => mime::IMAGE_JPEG,
+            "png" => mime::IMAGE_PNG,
+            _ => {
+                return Err(Error::BotError(format!(
+                    "unsupported avatar image type \"{}\"",
+                    extension
+                )))
+            }
+        };
+
+        self.client
+            .upload_avatar(&content_type, &mut Cursor::new(image))
+            .await?;
+        store.set_custom_value(AVATAR_KEY, digest).await?;
+        event!(Level::INFO, "updated avatar from {}", path.display());
+
+        Ok(())
+    }
+
+    /// Moves to the replacements of any rooms upgraded while the bot wasn't
+    /// running.
+    pub(crate) async fn follow_upgrades(&self) {
+        if let Err(e) = self.upgrades.load().await {
+            event!(Level::WARN, "failed to load room upgrades: {}", e);
+        }
+
+        for room in self.client.joined_rooms() {
+            if let Some(tombstone) = room.tombstone() {
+                let successor = tombstone.replacement_room;
+                if let Err(e) = self
+                    .upgrades
+                    .follow(&room, &successor, &self.joins, &self.scheduler)
+                    .await
+                {
+                    event!(
+                        Level::WARN,
+                        "failed to follow room {} to {}: {}",
+                        room.room_id(),
+                        successor,
+                        e
+                    );
                 }
             }
         }
+    }
+
+    pub(crate) async fn apply_room_display_names(&self) {
+        for room in self.client.joined_rooms() {
+            if let Err(e) = Self::set_room_display_name(&self.client, &room, &self.settings).await {
+                event!(
+                    Level::WARN,
+                    "failed to set display name in {}: {}",
+                    room.room_id(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Sets the bot's display name in `room` to the one configured for it.
+    async fn set_room_display_name(
+        client: &Client,
+        room: &Joined,
+        settings: &RoomSettings,
+    ) -> Result<()> {
+        let name = settings.display_name(room.room_id());
+        let user_id = client.user_id().await.unwrap();
+        let member = match room.get_member(&user_id).await? {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        if member.display_name() == Some(name) {
+            return Ok(());
+        }
 
-        let hcli = self.client.clone();
-        let config = self.config.clone();
+        let mut content = MemberEventContent::new(MembershipState::Join);
+        content.displayname = Some(name.into());
+        content.avatar_url = member.avatar_url().cloned();
+        room.send_state_event(AnyStateEventContent::RoomMember(content), user_id.as_str())
+            .await?;
+        event!(
+            Level::INFO,
+            "set display name in \"{}\" to {}",
+            room_name_or_id(&Room::from(room.clone())).await,
+            name
+        );
+
+        Ok(())
+    }
+
+    pub(crate) async fn register_event_handlers(&mut self) {
+        let user_id = self.client.user_id().await.unwrap();
+        let mentions = Arc::new(MentionDetector::new(&user_id, self.account.display_names()));
+        if let Err(e) = self.ignore.load().await {
+            event!(Level::WARN, "failed to load ignored users: {}", e);
+        }
+        let permissions = Arc::new(Permissions::new(self.client.clone(), self.account.clone()));
+        let handlers = Arc::new(handlers::get_handlers(
+            &self.client,
+            self.config.as_ref(),
+            &self.account,
+            BotState {
+                mentions,
+                invites: self.invites.clone(),
+                joins: self.joins.clone(),
+                feedback: self.feedback.clone(),
+                responses: self.responses.clone(),
+                scheduler: self.scheduler.clone(),
+                history: self.history.clone(),
+                karma: self.karma.clone(),
+                permissions: permissions.clone(),
+                ignore: self.ignore.clone(),
+            },
+        ));
+        self.handlers = Some(handlers.clone());
+        let breaker = Arc::new(CircuitBreaker::new(&self.account.circuit_breaker));
+        let mut pipeline = Pipeline::new();
+        pipeline.push(self.ignore.clone());
+        pipeline.push(breaker.clone());
+        pipeline.push(permissions);
+        pipeline.push(Arc::new(Cooldowns::new(self.account.cooldowns.clone())));
+        for layer in self.middleware.layers() {
+            pipeline.push(layer.clone());
+        }
+        // last, so that only messages the handlers will see are remembered
+        if self.account.handler_enabled(handlers::quote::NAME) {
+            pipeline.push(self.history.clone());
+        }
+        // karma changes count whichever handler responds to the message
+        if self.account.handler_enabled(handlers::karma::NAME) {
+            pipeline.push(self.karma.clone());
+        }
+        let dispatch = Arc::new(Dispatch {
+            handlers,
+            settings: self.settings.clone(),
+            responses: self.responses.clone(),
+            feedback: self.feedback.clone(),
+            breaker,
+            timeouts: self.account.timeouts.clone(),
+            pipeline,
+            in_flight: self.in_flight.clone(),
+        });
+
+        let d = dispatch.clone();
+        self.client
+            .register_event_handler(move |ev, room, client, raw: RawEvent| {
+                Self::on_room_message(ev, room, client, raw, d.clone())
+            })
+            .await;
+
+        let d = dispatch.clone();
+        self.client
+            .register_event_handler(move |ev, room, client| {
+                Self::on_reaction(ev, room, client, d.clone())
+            })
+            .await;
+
+        let feedback = self.feedback.clone();
+        self.client
+            .register_event_handler(move |ev, raw: RawEvent| {
+                Self::on_redaction(ev, raw, feedback.clone())
+            })
+            .await;
+
+        let settings = self.settings.clone();
+        let empty_rooms = self.empty_rooms.clone();
+        let welcomer = self.welcomer.clone();
         self.client
             .register_event_handler(move |ev, room, client| {
-                Self::on_room_message(
+                Self::on_room_member(
                     ev,
                     room,
                     client,
-                    handlers::get_handlers(&hcli, config.as_ref()),
+                    settings.clone(),
+                    empty_rooms.clone(),
+                    welcomer.clone(),
+                    dispatch.clone(),
+                )
+            })
+            .await;
+
+        let spaces = self.spaces.clone();
+        let joins = self.joins.clone();
+        self.client
+            .register_event_handler(move |ev, room| {
+                Self::on_space_child(ev, room, spaces.clone(), joins.clone())
+            })
+            .await;
+
+        let upgrades = self.upgrades.clone();
+        let joins = self.joins.clone();
+        let scheduler = self.scheduler.clone();
+        self.client
+            .register_event_handler(move |ev, room| {
+                Self::on_room_tombstone(
+                    ev,
+                    room,
+                    upgrades.clone(),
+                    joins.clone(),
+                    scheduler.clone(),
                 )
             })
             .await;
 
+        let upgrades = self.upgrades.clone();
         self.client
-            .register_event_handler(Self::on_stripped_state_member)
+            .register_event_handler(move |ev, room, client| {
+                Self::on_successor_joined(ev, room, client, upgrades.clone())
+            })
             .await;
+
         event!(Level::DEBUG, "registered event handlers");
+    }
 
-        Ok(())
+    /// Starts the scheduler, then calls each handler's `on_start`, giving
+    /// it the bot's background tasks.
+    pub(crate) async fn start_handlers(&self) {
+        let scheduler = self.scheduler.clone();
+        self.tasks
+            .spawn("scheduler", move || scheduler.clone().run());
+
+        let handlers = match &self.handlers {
+            Some(h) => h,
+            None => return,
+        };
+        let ctx = Context {
+            client: self.client.clone(),
+            tasks: self.tasks.clone(),
+            scheduler: self.scheduler.clone(),
+        };
+        for h in handlers.iter() {
+            let limit = Duration::from_secs(self.account.timeouts.get(h.name()));
+            if timeout(limit, h.on_start(&ctx)).await.is_err() {
+                event!(Level::WARN, "{} timed out starting up", h.name());
+            }
+        }
+    }
+
+    /// Cancels the background tasks, then calls each handler's
+    /// `on_shutdown`.
+    async fn stop_handlers(&self) {
+        self.tasks.stop();
+        let handlers = match &self.handlers {
+            Some(h) => h,
+            None => return,
+        };
+        for h in handlers.iter() {
+            let limit = Duration::from_secs(self.account.timeouts.get(h.name()));
+            if timeout(limit, h.on_shutdown()).await.is_err() {
+                event!(Level::WARN, "{} timed out shutting down", h.name());
+            }
+        }
+    }
+
+    pub(crate) async fn register_invite_handler(&self) {
+        let invites = self.invites.clone();
+        let joins = self.joins.clone();
+        self.client
+            .register_event_handler(move |ev, client, room| {
+                Self::on_stripped_state_member(ev, client, room, invites.clone(), joins.clone())
+            })
+            .await;
     }
 
     pub async fn sync(&self) -> Result<()> {
         event!(Level::DEBUG, "performing sync");
         let settings = SyncSettings::default().token(self.client.sync_token().await.unwrap());
-        self.client.sync(settings).await;
+        let retries = tokio::spawn(self.joins.clone().run());
+        tokio::select! {
+            _ = self.client.sync(settings) => {}
+            _ = self.shutdown.wait() => event!(Level::INFO, "shutting down"),
+        }
+        retries.abort();
+        self.tasks.stop();
         event!(Level::DEBUG, "sync finished");
+
+        if self.shutdown.is_triggered() {
+            self.finish().await;
+        } else {
+            self.stop_handlers().await;
+        }
+        Ok(())
+    }
+
+    /// Finishes up once shutdown has begun: waits for responses in progress,
+    /// stops the handlers, goes offline if configured to, and flushes the
+    /// state store.
+    pub(crate) async fn finish(&self) {
+        let limit = self.account.shutdown.timeout;
+        if !self.in_flight.drain(Duration::from_secs(limit)).await {
+            event!(
+                Level::WARN,
+                "gave up waiting for responses in progress after {}s",
+                limit
+            );
+        }
+        self.stop_handlers().await;
+
+        if self.account.shutdown.set_offline {
+            if let Err(e) = self.set_offline().await {
+                event!(Level::WARN, "failed to set presence to offline: {}", e);
+            }
+        }
+
+        // writing a custom value flushes the whole store to disk
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if let Err(e) = self
+            .client
+            .store()
+            .set_custom_value(LAST_SHUTDOWN_KEY, now.to_string().into_bytes())
+            .await
+        {
+            event!(Level::WARN, "failed to flush the state store: {}", e);
+        }
+
+        event!(Level::INFO, "shut down cleanly");
+    }
+
+    async fn set_offline(&self) -> Result<()> {
+        let user_id = match self.client.user_id().await {
+            Some(u) => u,
+            None => return Ok(()),
+        };
+        let request = set_presence::Request::new(&user_id, PresenceState::Offline);
+        self.client.send(request, None).await?;
         Ok(())
     }
 
@@ -166,65 +709,498 @@ impl BingoBot {
         event: SyncMessageEvent<MessageEventContent>,
         client: Client,
         room: Room,
-        handlers: Vec<Box<dyn handlers::Handler>>,
+        raw: RawEvent,
+        dispatch: Arc<Dispatch>,
     ) {
         if let Room::Joined(room) = room {
-            if let SyncMessageEvent {
-                content:
-                    MessageEventContent {
-                        msgtype: MessageType::Text(TextMessageEventContent { body: msg_body, .. }),
-                        ..
-                    },
+            let SyncMessageEvent {
+                content,
                 sender,
+                event_id,
                 ..
-            } = event
+            } = event;
+            let is_edit = matches!(content.relates_to, Some(Relation::Replacement(_)));
+            let (kind, msg_body, formatted) = match content.msgtype {
+                MessageType::Text(c) => (MessageKind::Text, c.body, c.formatted),
+                MessageType::Emote(c) => (MessageKind::Emote, c.body, c.formatted),
+                MessageType::Notice(c) => (MessageKind::Notice, c.body, c.formatted),
+                _ => return,
+            };
+
+            let member = room.get_member(&sender).await.unwrap().unwrap();
+            let room_name = room.name().unwrap_or_else(|| room.room_id().to_string());
+
+            event!(
+                Level::INFO,
+                room = room_name.as_str(),
+                sender = member.user_id().as_str(),
+                kind = ?kind,
+                msg = msg_body.as_str(),
+            );
+
+            if sender == client.user_id().await.unwrap() {
+                return;
+            }
+            let message = Message {
+                kind,
+                room_id: room.room_id().clone(),
+                event_id,
+                sender_name: member
+                    .display_name()
+                    .unwrap_or_else(|| member.user_id().as_str())
+                    .into(),
+                sender,
+                body: msg_body,
+                formatted_body: formatted.map(|f| f.body),
+                mentions: RawMessage::mentions(raw.get()),
+                is_edit,
+            };
+            let message = match dispatch.pipeline.before(message).await {
+                Some(m) => m,
+                None => return,
+            };
+
+            // no new responses once shutdown has begun
+            let task = match dispatch.in_flight.start() {
+                Some(t) => t,
+                None => return,
+            };
+            tokio::spawn(Self::dispatch_message(room, message, dispatch, task));
+        }
+    }
+
+    /// Runs every handler that claims `message` at once, each under its own
+    /// timeout, and sends the response of the first of them, in handler
+    /// order, that has one. Handlers aren't cut off when another responds
+    /// first or when they overrun; their responses are just dropped.
+    async fn dispatch_message(room: Joined, message: Message, dispatch: Arc<Dispatch>, task: Task) {
+        // what to say if none of the claiming handlers respond
+        let mut fallback = None;
+        let mut allowed = Vec::new();
+        let claims = dispatch.handlers.claims(&message).filter(|(i, _)| {
+            let h = dispatch.handlers.get(*i);
+            h.accepts(message.kind) && dispatch.settings.handler_enabled(room.room_id(), h.name())
+        });
+        for (i, m) in claims {
+            match dispatch
+                .pipeline
+                .allow(&message, dispatch.handlers.get(i))
+                .await
             {
-                let member = room.get_member(&sender).await.unwrap().unwrap();
-                let room_name = room.name().unwrap_or_else(|| room.room_id().to_string());
+                Verdict::Allow => allowed.push((i, m)),
+                Verdict::Deny(reply) => fallback = fallback.or(reply),
+            }
+        }
 
-                event!(
-                    Level::INFO,
-                    room = room_name.as_str(),
-                    sender = member.user_id().as_str(),
-                    msg = msg_body.as_str(),
-                );
+        let runs = dispatch.handlers.run(allowed, &dispatch.timeouts, &task);
+        let mut chosen = None;
+        for (i, run) in runs {
+            let h = dispatch.handlers.get(i);
+            if chosen.is_some() {
+                // a handler before this one already answered
+                dispatch.pipeline.release(&message, h).await;
+                continue;
+            }
+            match run.await {
+                Ok(Outcome::Responded(r)) => chosen = Some((h, r)),
+                Ok(Outcome::TimedOut) if dispatch.timeouts.report => {
+                    fallback = Some(format!(
+                        "Sorry, {}, that took too long. Try again later?",
+                        message.sender_name
+                    ));
+                    dispatch.pipeline.release(&message, h).await;
+                }
+                _ => dispatch.pipeline.release(&message, h).await,
+            }
+        }
 
-                if sender == client.user_id().await.unwrap() {
-                    return;
+        let (h, response) = match chosen {
+            Some(c) => c,
+            None => {
+                if let Some(content) = fallback.and_then(handlers::new_message) {
+                    Self::respond(&room, content, &dispatch).await;
                 }
+                return;
+            }
+        };
+        let content = match dispatch.pipeline.after(&message, h, response).await {
+            Some(c) => c,
+            None => return dispatch.pipeline.release(&message, h).await,
+        };
+        let sent = Self::respond(&room, content, &dispatch).await;
+        if let (Some(event_id), true) = (sent, h.undoable()) {
+            dispatch.responses.record(Response {
+                room_id: room.room_id().clone(),
+                event_id,
+                handler: h.name().into(),
+                trigger: message.clone(),
+            });
+        }
+    }
 
-                let sender_name = member
-                    .display_name()
-                    .unwrap_or_else(|| member.user_id().as_str());
+    /// Sends a handler's response, returning its event ID.
+    async fn respond(
+        room: &Joined,
+        content: AnyMessageEventContent,
+        dispatch: &Dispatch,
+    ) -> Option<EventId> {
+        let typing = room.typing_notice(true).await.is_ok();
 
-                for h in handlers {
-                    if let Some(content) = h.handle(sender_name, &msg_body).await {
-                        let typing = room.typing_notice(true).await.is_ok();
+        let millis = fastrand::u64(500..=1500);
+        sleep(Duration::from_millis(millis)).await;
+        let sent = room.send(content, None).await;
 
-                        let millis = fastrand::u64(500..=1500);
-                        sleep(Duration::from_millis(millis)).await;
-                        room.send(content, None).await.unwrap();
+        if typing {
+            room.typing_notice(false).await.unwrap();
+        }
 
-                        if typing {
-                            room.typing_notice(false).await.unwrap();
-                        }
-                        break;
+        match sent {
+            Ok(r) => {
+                dispatch.breaker.record(room.room_id());
+                Some(r.event_id)
+            }
+            Err(e) => {
+                event!(Level::ERROR, "failed to send response: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Acts on reactions to the bot's responses: ❌ redacts the response, 👍
+    /// and 👎 count as feedback, and anything else goes to the handler that
+    /// sent it.
+    async fn on_reaction(
+        event: SyncMessageEvent<ReactionEventContent>,
+        room: Room,
+        client: Client,
+        dispatch: Arc<Dispatch>,
+    ) {
+        let room = match room {
+            Room::Joined(r) => r,
+            _ => return,
+        };
+        if event.sender == client.user_id().await.unwrap() {
+            return;
+        }
+        let _task = match dispatch.in_flight.start() {
+            Some(t) => t,
+            None => return,
+        };
+
+        let responses = &dispatch.responses;
+        let relation = event.content.relates_to;
+        let response = match responses.get(room.room_id(), &relation.event_id) {
+            Some(r) => r,
+            None => return,
+        };
+        let key = relation
+            .emoji
+            .trim_end_matches(|c| c == '\u{fe0f}' || ('\u{1f3fb}'..='\u{1f3ff}').contains(&c));
+
+        let sender_name = match room.get_member(&event.sender).await {
+            Ok(Some(member)) => member
+                .display_name()
+                .unwrap_or_else(|| member.user_id().as_str())
+                .into(),
+            _ => event.sender.to_string(),
+        };
+        let message = Message {
+            kind: MessageKind::Reaction,
+            room_id: room.room_id().clone(),
+            event_id: event.event_id,
+            sender_name,
+            sender: event.sender,
+            body: key.into(),
+            formatted_body: None,
+            mentions: None,
+            is_edit: false,
+        };
+        let message = match dispatch.pipeline.before(message).await {
+            Some(m) => m,
+            None => return,
+        };
+
+        match &message.body[..] {
+            "❌" => {
+                if !Self::may_redact(&room, &message.sender, &response).await {
+                    event!(
+                        Level::DEBUG,
+                        "{} may not redact response {}",
+                        message.sender,
+                        response.event_id
+                    );
+                    return;
+                }
+                match room
+                    .redact(&response.event_id, Some("removed by reaction"), None)
+                    .await
+                {
+                    Ok(_) => {
+                        responses.remove(room.room_id(), &response.event_id);
+                        event!(
+                            Level::INFO,
+                            "redacted response {} at the request of {}",
+                            response.event_id,
+                            message.sender
+                        );
                     }
+                    Err(e) => event!(
+                        Level::WARN,
+                        "failed to redact response {}: {}",
+                        response.event_id,
+                        e
+                    ),
+                }
+            }
+            "👍" | "👎" => {
+                let up = message.body == "👍";
+                if let Err(e) = dispatch
+                    .feedback
+                    .record(
+                        &response.handler,
+                        &response.event_id,
+                        &message.sender,
+                        &message.event_id,
+                        up,
+                    )
+                    .await
+                {
+                    event!(Level::WARN, "failed to record feedback: {}", e);
+                }
+            }
+            _ => {
+                let handler = match dispatch.handlers.find(&response.handler) {
+                    Some(h) => h,
+                    None => return,
+                };
+                if dispatch.pipeline.allow(&message, handler).await != Verdict::Allow {
+                    return;
+                }
+                let reaction = Reaction {
+                    sender: message.sender.clone(),
+                    key: message.body.clone(),
+                    trigger: response.trigger.clone(),
+                };
+                let limit = Duration::from_secs(dispatch.timeouts.get(handler.name()));
+                let content = match timeout(limit, handler.on_reaction(&reaction)).await {
+                    Ok(Some(c)) => dispatch.pipeline.after(&message, handler, c).await,
+                    Ok(None) => None,
+                    Err(_) => {
+                        event!(
+                            Level::WARN,
+                            "{} timed out handling a reaction",
+                            handler.name()
+                        );
+                        None
+                    }
+                };
+                let content = match content {
+                    Some(c) => c,
+                    None => return dispatch.pipeline.release(&message, handler).await,
+                };
+                if let Some(event_id) = Self::respond(&room, content, &dispatch).await {
+                    responses.record(Response {
+                        event_id,
+                        ..response
+                    });
                 }
             }
         }
     }
 
+    /// Takes back feedback when the reaction that gave it is redacted.
+    async fn on_redaction(
+        _: SyncMessageEvent<RedactionEventContent>,
+        raw: RawEvent,
+        feedback: Arc<Feedback>,
+    ) {
+        // the redacted event's ID is outside the content, so the typed event
+        // doesn't have it
+        let redaction = match serde_json::from_str::<SyncRedactionEvent>(raw.get()) {
+            Ok(r) => r,
+            Err(e) => {
+                event!(Level::WARN, "failed to parse redaction: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = feedback.retract(&redaction.redacts).await {
+            event!(Level::WARN, "failed to take back feedback: {}", e);
+        }
+    }
+
+    /// Returns whether `user` may have the bot redact `response`: they must
+    /// have asked for it, or be a room moderator.
+    async fn may_redact(room: &Joined, user: &UserId, response: &Response) -> bool {
+        if &response.trigger.sender == user {
+            return true;
+        }
+        match room.get_member(user).await {
+            Ok(Some(member)) => member.power_level() >= MODERATOR_POWER_LEVEL,
+            _ => false,
+        }
+    }
+
+    /// Reapplies the bot's per-room display name and tells handlers
+    /// whenever it joins a room, welcomes other members as they join, and
+    /// notices when everyone else has left.
+    async fn on_room_member(
+        room_member: SyncStateEvent<MemberEventContent>,
+        room: Room,
+        client: Client,
+        settings: Arc<RoomSettings>,
+        empty_rooms: Arc<EmptyRooms>,
+        welcomer: Arc<Welcomer>,
+        dispatch: Arc<Dispatch>,
+    ) {
+        let was_joined = matches!(
+            &room_member.prev_content,
+            Some(MemberEventContent {
+                membership: MembershipState::Join,
+                ..
+            })
+        );
+        if room_member.state_key != client.user_id().await.unwrap().as_str() {
+            if let Room::Joined(room) = &room {
+                match room_member.content.membership {
+                    MembershipState::Join if !was_joined => {
+                        let name = room_member
+                            .content
+                            .displayname
+                            .as_deref()
+                            .unwrap_or(&room_member.state_key);
+                        welcomer.welcome(room, name).await;
+                    }
+                    MembershipState::Leave | MembershipState::Ban => {
+                        empty_rooms.check(room).await;
+                    }
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        if room_member.content.membership != MembershipState::Join {
+            return;
+        }
+
+        if let Room::Joined(room) = room {
+            if let Err(e) = Self::set_room_display_name(&client, &room, &settings).await {
+                event!(
+                    Level::WARN,
+                    "failed to set display name in {}: {}",
+                    room.room_id(),
+                    e
+                );
+            }
+            if !was_joined {
+                tokio::spawn(Self::on_joined(room, dispatch));
+            }
+        }
+    }
+
+    /// Calls `on_room_join` for each handler enabled in `room`.
+    async fn on_joined(room: Joined, dispatch: Arc<Dispatch>) {
+        let handlers = dispatch
+            .handlers
+            .iter()
+            .filter(|h| dispatch.settings.handler_enabled(room.room_id(), h.name()));
+        for h in handlers {
+            let limit = Duration::from_secs(dispatch.timeouts.get(h.name()));
+            if timeout(limit, h.on_room_join(&room)).await.is_err() {
+                event!(
+                    Level::WARN,
+                    "{} timed out handling a join to {}",
+                    h.name(),
+                    room.room_id()
+                );
+            }
+        }
+    }
+
+    async fn on_room_tombstone(
+        tombstone: SyncStateEvent<TombstoneEventContent>,
+        room: Room,
+        upgrades: Arc<RoomUpgrades>,
+        joins: Arc<JoinQueue>,
+        scheduler: Arc<Scheduler>,
+    ) {
+        if let Room::Joined(room) = room {
+            let successor = tombstone.content.replacement_room;
+            if let Err(e) = upgrades.follow(&room, &successor, &joins, &scheduler).await {
+                event!(
+                    Level::WARN,
+                    "failed to follow room {} to {}: {}",
+                    room.room_id(),
+                    successor,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Leaves a room once the bot has joined the room that replaced it, if
+    /// that join had to wait.
+    async fn on_successor_joined(
+        room_member: SyncStateEvent<MemberEventContent>,
+        room: Room,
+        client: Client,
+        upgrades: Arc<RoomUpgrades>,
+    ) {
+        if room_member.state_key != client.user_id().await.unwrap().as_str()
+            || room_member.content.membership != MembershipState::Join
+        {
+            return;
+        }
+        if let Err(e) = upgrades.leave_predecessor(room.room_id()).await {
+            event!(
+                Level::WARN,
+                "failed to leave the room {} replaced: {}",
+                room.room_id(),
+                e
+            );
+        }
+    }
+
+    /// Joins rooms as they're added to a configured space.
+    async fn on_space_child(
+        child: SyncStateEvent<ChildEventContent>,
+        room: Room,
+        spaces: Arc<Spaces>,
+        joins: Arc<JoinQueue>,
+    ) {
+        if !spaces.is_configured(room.room_id()) {
+            return;
+        }
+        let child_id = match RoomId::try_from(child.state_key.as_str()) {
+            Ok(id) => id,
+            Err(_) => return,
+        };
+
+        if matches!(&child.content.via, Some(v) if !v.is_empty()) {
+            spaces.add_child(room.room_id(), &child_id, &joins).await;
+        } else {
+            spaces.remove_child(room.room_id(), &child_id);
+        }
+    }
+
     async fn on_stripped_state_member(
         room_member: StrippedStateEvent<MemberEventContent>,
         client: Client,
         room: Room,
+        invites: Arc<Invites>,
+        joins: Arc<JoinQueue>,
     ) {
-        if room_member.state_key != client.user_id().await.unwrap() {
+        if room_member.state_key != client.user_id().await.unwrap()
+            || room_member.content.membership != MembershipState::Invite
+        {
             return;
         }
 
         if let Room::Invited(room) = room {
+            if !invites.review(room.room_id(), &room_member.sender).await {
+                return;
+            }
+
             let display_name = room.display_name().await;
             let name = match &display_name {
                 Ok(n) => n,
@@ -232,34 +1208,10 @@ impl BingoBot {
             };
             event!(Level::INFO, "auto-joining room {}", name);
 
-            let mut delay = 2;
-
-            while let Err(err) = room.accept_invitation().await {
-                event!(
-                    Level::ERROR,
-                    "failed to join room {} ({:?}), retrying in {}s",
-                    room.room_id(),
-                    err,
-                    delay
-                );
-
-                sleep(Duration::from_secs(delay)).await;
-                delay *= 2;
-                if delay > 3600 {
-                    event!(
-                        Level::ERROR,
-                        "can't join room {} ({:?})",
-                        room.room_id(),
-                        err
-                    );
-                    break;
-                }
+            match room.accept_invitation().await {
+                Ok(()) => event!(Level::INFO, "joined room {}", name),
+                Err(e) => joins.add(room.room_id(), &e.into()).await,
             }
-            event!(
-                Level::ERROR,
-                "successfully joined room {}",
-                room.name().unwrap_or_else(|| room.room_id().to_string())
-            );
         }
     }
 }
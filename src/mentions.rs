@@ -0,0 +1,246 @@
+use matrix_sdk::ruma::UserId;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::handlers::Message;
+
+/// Decides whether a message is addressed to the bot.
+///
+/// Messages carrying `m.mentions` metadata are trusted as-is. Otherwise the
+/// bot counts as mentioned by a pill linking to its user ID in the formatted
+/// body, by its user ID in the plain body, or by its localpart or one of its
+/// display names set apart from the words around it. A name followed by `!`
+/// ("bingo!") is taken to be an exclamation rather than a mention, unless it
+/// is written `@name`.
+#[derive(Debug)]
+pub struct MentionDetector {
+    user_id: UserId,
+    names: Regex,
+    pill: Regex,
+}
+
+impl MentionDetector {
+    pub fn new<'a>(user_id: &UserId, display_names: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut names: Vec<String> = display_names
+            .into_iter()
+            .filter(|n| !n.is_empty())
+            .map(regex::escape)
+            .collect();
+        names.push(regex::escape(user_id.localpart()));
+        // longest first, so "Bingo Bot" wins over "Bingo"
+        names.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        names.dedup();
+
+        // The boundaries around a name are checked by `named`, since a
+        // display name can start or end with something other than a word
+        // character.
+        let names = Regex::new(&format!(
+            r"(?i)(?P<at>@?)(?:{})(?P<bang>!?)",
+            names.join("|")
+        ))
+        .unwrap();
+
+        let pill = Regex::new(&format!(
+            r#"(?i)href=["']https://matrix\.to/#/(?:@|%40){}(?::|%3A){}["'?]"#,
+            regex::escape(user_id.localpart()),
+            regex::escape(user_id.server_name().as_str())
+        ))
+        .unwrap();
+
+        Self {
+            user_id: user_id.clone(),
+            names,
+            pill,
+        }
+    }
+
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// Returns whether `message` mentions the bot.
+    pub fn is_mentioned(&self, message: &Message) -> bool {
+        if let Some(mentions) = &message.mentions {
+            return mentions.contains(&self.user_id);
+        }
+
+        if let Some(html) = &message.formatted_body {
+            if self.pill.is_match(html) {
+                return true;
+            }
+        }
+
+        if contains_user_id(&message.body, &self.user_id) {
+            return true;
+        }
+
+        self.named(&message.body).any(|(at, bang)| at || !bang)
+    }
+
+    /// Returns whether `text` names the bot, such as the target of a command.
+    pub fn refers_to_bot(&self, text: &str) -> bool {
+        contains_user_id(text, &self.user_id) || self.named(text).next().is_some()
+    }
+
+    /// Finds the bot's names in `text` that aren't part of a longer word or
+    /// someone else's user ID, with whether each was written with a leading
+    /// `@` and a trailing `!`.
+    fn named<'a>(&'a self, text: &'a str) -> impl Iterator<Item = (bool, bool)> + 'a {
+        self.names.captures_iter(text).filter_map(move |c| {
+            let whole = c.get(0).unwrap();
+            let before = text[..whole.start()].chars().next_back();
+            let mut after = text[whole.end()..].chars();
+            // `:` and more is the server name of someone else's user ID
+            let part_of_id = match after.next() {
+                Some(a) if is_word(a) || a == '@' => true,
+                Some(':') => matches!(after.next(), Some(n) if n.is_alphanumeric()),
+                _ => false,
+            };
+            if part_of_id || matches!(before, Some(b) if is_word(b) || b == '@') {
+                return None;
+            }
+            Some((!c["at"].is_empty(), !c["bang"].is_empty()))
+        })
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Returns whether `text` contains `user_id` as a whole, rather than as part
+/// of a longer ID such as `@bingo:example.org.evil`. Punctuation after the
+/// ID, as in "thanks @bingo:example.org!", is fine.
+fn contains_user_id(text: &str, user_id: &UserId) -> bool {
+    let id = user_id.as_str();
+    text.match_indices(id).any(|(start, _)| {
+        let mut before = text[..start].chars();
+        let mut after = text[start + id.len()..].chars();
+        let starts_id = match before.next_back() {
+            Some(c) => !(c.is_alphanumeric() || "._=-/@".contains(c)),
+            None => true,
+        };
+        let ends_id = match after.next() {
+            None => true,
+            Some(c) if c.is_alphanumeric() => false,
+            // a separator followed by more of an ID, as in `.evil` or `:8448`
+            Some(c) if ".-:".contains(c) => !matches!(after.next(), Some(n) if n.is_alphanumeric()),
+            Some(_) => true,
+        };
+        starts_id && ends_id
+    })
+}
+
+/// The parts of a raw message event that the SDK's types don't cover.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RawMessage {
+    content: RawContent,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawContent {
+    #[serde(rename = "m.mentions")]
+    mentions: Option<RawMentions>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawMentions {
+    #[serde(default)]
+    user_ids: Vec<UserId>,
+}
+
+impl RawMessage {
+    /// Returns the users listed in the message's `m.mentions`, if it has any.
+    pub(crate) fn mentions(json: &str) -> Option<Vec<UserId>> {
+        serde_json::from_str::<Self>(json)
+            .ok()?
+            .content
+            .mentions
+            .map(|m| m.user_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use matrix_sdk::ruma::{EventId, RoomId};
+
+    use super::*;
+    use crate::handlers::MessageKind;
+
+    fn detector() -> MentionDetector {
+        let user_id = UserId::try_from("@bingo:example.org").unwrap();
+        MentionDetector::new(&user_id, vec!["Bingo Bot", "bot)", ""])
+    }
+
+    fn message(body: &str) -> Message {
+        Message {
+            kind: MessageKind::Text,
+            room_id: RoomId::try_from("!room:example.org").unwrap(),
+            event_id: EventId::try_from("$event:example.org").unwrap(),
+            sender: UserId::try_from("@someone:example.org").unwrap(),
+            sender_name: "someone".into(),
+            body: body.into(),
+            formatted_body: None,
+            mentions: None,
+            is_edit: false,
+        }
+    }
+
+    fn mentioned(body: &str) -> bool {
+        detector().is_mentioned(&message(body))
+    }
+
+    #[test]
+    fn finds_names() {
+        assert!(mentioned("bingo, what's up?"));
+        assert!(mentioned("hey Bingo Bot"));
+        assert!(mentioned("thanks @bingo!"));
+        assert!(mentioned("(ask the bot) about it"));
+        assert!(mentioned("hi bot)"));
+        assert!(mentioned("bingo! oh and bingo, hello"));
+
+        assert!(!mentioned("bingo!"));
+        assert!(!mentioned("bingos are fun"));
+        assert!(!mentioned("lucky_bingo"));
+        assert!(!mentioned("email bingo@example.org"));
+        assert!(!mentioned("the robot) did it"));
+    }
+
+    #[test]
+    fn matches_whole_user_ids() {
+        assert!(mentioned("@bingo:example.org"));
+        assert!(mentioned("ping @bingo:example.org: hello"));
+        assert!(mentioned("thanks (@bingo:example.org)."));
+
+        let d = detector();
+        assert!(!d.refers_to_bot("@bingo:example.org.evil"));
+        assert!(!d.refers_to_bot("@bingo:example.org:8448"));
+        assert!(!d.refers_to_bot("@notbingo:example.org"));
+        assert!(!d.refers_to_bot("@bingo:example.com"));
+        assert!(d.refers_to_bot("@bingo:example.org's"));
+    }
+
+    #[test]
+    fn trusts_mentions_metadata() {
+        let d = detector();
+        let mut m = message("bingo");
+        m.mentions = Some(vec![]);
+        assert!(!d.is_mentioned(&m));
+        m.mentions = Some(vec![d.user_id().clone()]);
+        m.body = "hello".into();
+        assert!(d.is_mentioned(&m));
+    }
+
+    #[test]
+    fn finds_pills() {
+        let d = detector();
+        let mut m = message("hello");
+        m.formatted_body = Some(r#"<a href="https://matrix.to/#/@bingo:example.org">B</a>"#.into());
+        assert!(d.is_mentioned(&m));
+        m.formatted_body =
+            Some(r#"<a href="https://matrix.to/#/@bingo:example.org.evil">B</a>"#.into());
+        assert!(!d.is_mentioned(&m));
+    }
+}
@@ -0,0 +1,432 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use tracing::{event, Level};
+
+use crate::handlers::{Handler, Message};
+
+/// Whether a handler may handle a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The handler may not handle the message. The reply, if there is one,
+    /// is sent if no other handler responds.
+    Deny(Option<String>),
+}
+
+/// A layer around handler dispatch. Layers see every incoming message and
+/// reaction before any handler does, decide which handlers may handle it,
+/// and see every response before it's sent.
+#[async_trait]
+pub trait Middleware: Send + Sync + std::fmt::Debug {
+    /// A name for the layer, used in logs.
+    fn name(&self) -> &str;
+
+    /// Called with each incoming message before it's dispatched. Returns the
+    /// message to pass on, which may be rewritten, or `None` to drop it.
+    async fn before(&self, message: Message) -> Option<Message> {
+        Some(message)
+    }
+
+    /// Called before `handler` handles `message`, once it has claimed it.
+    async fn allow(&self, _message: &Message, _handler: &dyn Handler) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// Called when `handler` was allowed to handle `message` but nothing it
+    /// said was sent: it had nothing to say, timed out, was beaten to it by a
+    /// handler before it, or had its response vetoed. Also called on the
+    /// layers that allowed it when a later layer denies it. Layers can undo
+    /// anything they did in `allow`.
+    async fn release(&self, _message: &Message, _handler: &dyn Handler) {}
+
+    /// Called with the response `handler` gave to `message`, before it's
+    /// sent. Returns the response to send, which may be rewritten, or `None`
+    /// to veto it.
+    async fn after(
+        &self,
+        _message: &Message,
+        _handler: &dyn Handler,
+        response: AnyMessageEventContent,
+    ) -> Option<AnyMessageEventContent> {
+        Some(response)
+    }
+}
+
+/// Middleware layers, run in the order they were added on the way in and in
+/// reverse on the way out.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, layer: Arc<dyn Middleware>) {
+        self.layers.push(layer);
+    }
+
+    pub fn layers(&self) -> &[Arc<dyn Middleware>] {
+        &self.layers
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Runs `message` through each layer's `before`, stopping at the first
+    /// that drops it.
+    pub async fn before(&self, mut message: Message) -> Option<Message> {
+        for layer in &self.layers {
+            message = match layer.before(message).await {
+                Some(m) => m,
+                None => {
+                    event!(Level::DEBUG, "{} dropped the message", layer.name());
+                    return None;
+                }
+            };
+        }
+        Some(message)
+    }
+
+    /// Asks each layer whether `handler` may handle `message`, stopping at
+    /// the first that says no and releasing the layers that said yes.
+    pub async fn allow(&self, message: &Message, handler: &dyn Handler) -> Verdict {
+        for (n, layer) in self.layers.iter().enumerate() {
+            if let Verdict::Deny(reply) = layer.allow(message, handler).await {
+                event!(
+                    Level::DEBUG,
+                    "{} denied {} the message",
+                    layer.name(),
+                    handler.name()
+                );
+                for allowed in self.layers[..n].iter().rev() {
+                    allowed.release(message, handler).await;
+                }
+                return Verdict::Deny(reply);
+            }
+        }
+        Verdict::Allow
+    }
+
+    /// Tells each layer that nothing `handler` said in answer to `message`
+    /// was sent.
+    pub async fn release(&self, message: &Message, handler: &dyn Handler) {
+        for layer in self.layers.iter().rev() {
+            layer.release(message, handler).await;
+        }
+    }
+
+    /// Runs `handler`'s response to `message` through each layer's `after`,
+    /// stopping at the first that vetoes it.
+    pub async fn after(
+        &self,
+        message: &Message,
+        handler: &dyn Handler,
+        mut response: AnyMessageEventContent,
+    ) -> Option<AnyMessageEventContent> {
+        for layer in self.layers.iter().rev() {
+            response = match layer.after(message, handler, response).await {
+                Some(r) => r,
+                None => {
+                    event!(
+                        Level::DEBUG,
+                        "{} vetoed the response from {}",
+                        layer.name(),
+                        handler.name()
+                    );
+                    return None;
+                }
+            };
+        }
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Mutex;
+
+    use matrix_sdk::ruma::events::room::message::{MessageEventContent, MessageType};
+    use matrix_sdk::ruma::{EventId, RoomId, UserId};
+
+    use super::*;
+    use crate::handlers::{new_message, Match, MessageKind};
+
+    fn message(body: &str) -> Message {
+        Message {
+            kind: MessageKind::Text,
+            room_id: RoomId::try_from("!room:example.org").unwrap(),
+            event_id: EventId::try_from("$event:example.org").unwrap(),
+            sender: UserId::try_from("@someone:example.org").unwrap(),
+            sender_name: "someone".into(),
+            body: body.into(),
+            formatted_body: None,
+            mentions: None,
+            is_edit: false,
+        }
+    }
+
+    fn body(content: &AnyMessageEventContent) -> &str {
+        match content {
+            AnyMessageEventContent::RoomMessage(MessageEventContent {
+                msgtype: MessageType::Text(t),
+                ..
+            }) => &t.body,
+            _ => panic!("not a text message"),
+        }
+    }
+
+    /// A handler that only has a name.
+    #[derive(Debug)]
+    struct Named(&'static str);
+
+    #[async_trait]
+    impl Handler for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn cmd(&self) -> &str {
+            ""
+        }
+
+        fn description(&self) -> &str {
+            ""
+        }
+
+        async fn handle(&self, _m: &Match) -> Option<AnyMessageEventContent> {
+            None
+        }
+    }
+
+    /// Denies a handler, recording what it was asked about.
+    #[derive(Debug)]
+    struct Deny {
+        handler: &'static str,
+        asked: Mutex<Vec<String>>,
+        released: Mutex<Vec<String>>,
+    }
+
+    impl Deny {
+        fn new(handler: &'static str) -> Self {
+            Self {
+                handler,
+                asked: Mutex::new(Vec::new()),
+                released: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Middleware for Deny {
+        fn name(&self) -> &str {
+            "deny"
+        }
+
+        async fn allow(&self, _message: &Message, handler: &dyn Handler) -> Verdict {
+            self.asked.lock().unwrap().push(handler.name().into());
+            if handler.name() == self.handler {
+                Verdict::Deny(Some(format!("no {}", self.handler)))
+            } else {
+                Verdict::Allow
+            }
+        }
+
+        async fn release(&self, _message: &Message, handler: &dyn Handler) {
+            self.released.lock().unwrap().push(handler.name().into());
+        }
+    }
+
+    /// Strips a prefix from messages, and adds it to responses.
+    #[derive(Debug)]
+    struct Prefix(&'static str);
+
+    #[async_trait]
+    impl Middleware for Prefix {
+        fn name(&self) -> &str {
+            "prefix"
+        }
+
+        async fn before(&self, mut message: Message) -> Option<Message> {
+            if let Some(rest) = message.body.strip_prefix(self.0) {
+                message.body = rest.to_string();
+            }
+            Some(message)
+        }
+
+        async fn after(
+            &self,
+            _message: &Message,
+            _handler: &dyn Handler,
+            response: AnyMessageEventContent,
+        ) -> Option<AnyMessageEventContent> {
+            new_message(format!("{}{}", self.0, body(&response)))
+        }
+    }
+
+    /// Drops messages and vetoes responses containing a word, recording
+    /// what it saw.
+    #[derive(Debug)]
+    struct Block {
+        word: &'static str,
+        seen: Mutex<Vec<String>>,
+    }
+
+    impl Block {
+        fn new(word: &'static str) -> Self {
+            Self {
+                word,
+                seen: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Middleware for Block {
+        fn name(&self) -> &str {
+            "block"
+        }
+
+        async fn before(&self, message: Message) -> Option<Message> {
+            self.seen.lock().unwrap().push(message.body.clone());
+            if message.body.contains(self.word) {
+                None
+            } else {
+                Some(message)
+            }
+        }
+
+        async fn after(
+            &self,
+            _message: &Message,
+            _handler: &dyn Handler,
+            response: AnyMessageEventContent,
+        ) -> Option<AnyMessageEventContent> {
+            self.seen.lock().unwrap().push(body(&response).to_string());
+            if body(&response).contains(self.word) {
+                None
+            } else {
+                Some(response)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_pipeline_passes_everything() {
+        let pipeline = Pipeline::new();
+        assert!(pipeline.is_empty());
+
+        let m = pipeline.before(message("!slap bob")).await.unwrap();
+        assert_eq!(m.body, "!slap bob");
+
+        let r = pipeline
+            .after(&m, &Named("slap"), new_message("hi".into()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(body(&r), "hi");
+    }
+
+    #[tokio::test]
+    async fn before_runs_in_order() {
+        let block = Arc::new(Block::new("secret"));
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Arc::new(Prefix("bingo: ")));
+        pipeline.push(block.clone());
+        assert_eq!(pipeline.len(), 2);
+
+        let m = pipeline.before(message("bingo: !rfc 1149")).await.unwrap();
+        assert_eq!(m.body, "!rfc 1149");
+        // the later layer sees the rewritten message
+        assert_eq!(block.seen.lock().unwrap().as_slice(), ["!rfc 1149"]);
+    }
+
+    #[tokio::test]
+    async fn dropped_messages_skip_later_layers() {
+        let first = Arc::new(Block::new("secret"));
+        let second = Arc::new(Block::new("other"));
+        let mut pipeline = Pipeline::new();
+        pipeline.push(first.clone());
+        pipeline.push(second.clone());
+
+        assert!(pipeline.before(message("the secret word")).await.is_none());
+        assert_eq!(first.seen.lock().unwrap().len(), 1);
+        assert!(second.seen.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn allow_stops_at_the_first_denial() {
+        let first = Arc::new(Deny::new("python"));
+        let second = Arc::new(Deny::new("slap"));
+        let mut pipeline = Pipeline::new();
+        pipeline.push(first.clone());
+        pipeline.push(second.clone());
+
+        let m = message("python");
+        assert_eq!(pipeline.allow(&m, &Named("howdy")).await, Verdict::Allow);
+        assert_eq!(
+            pipeline.allow(&m, &Named("python")).await,
+            Verdict::Deny(Some("no python".into()))
+        );
+        assert_eq!(
+            pipeline.allow(&m, &Named("slap")).await,
+            Verdict::Deny(Some("no slap".into()))
+        );
+        assert_eq!(
+            first.asked.lock().unwrap().as_slice(),
+            ["howdy", "python", "slap"]
+        );
+        assert_eq!(second.asked.lock().unwrap().as_slice(), ["howdy", "slap"]);
+
+        // the first layer allowed slap before the second denied it
+        assert_eq!(first.released.lock().unwrap().as_slice(), ["slap"]);
+        assert!(second.released.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn after_runs_in_reverse() {
+        let block = Arc::new(Block::new("bingo: "));
+        let mut pipeline = Pipeline::new();
+        pipeline.push(block.clone());
+        pipeline.push(Arc::new(Prefix("bingo: ")));
+
+        // the prefix layer was added last, so it runs first and the block
+        // layer sees the prefixed response
+        let m = message("hello");
+        let r = pipeline
+            .after(&m, &Named("howdy"), new_message("Howdy!".into()).unwrap())
+            .await;
+        assert!(r.is_none());
+        assert_eq!(block.seen.lock().unwrap().as_slice(), ["bingo: Howdy!"]);
+    }
+
+    #[tokio::test]
+    async fn vetoed_responses_are_not_sent() {
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Arc::new(Block::new("python")));
+
+        let m = message("python");
+        let r = pipeline
+            .after(
+                &m,
+                &Named("python"),
+                new_message("I hate python".into()).unwrap(),
+            )
+            .await;
+        assert!(r.is_none());
+
+        let r = pipeline
+            .after(&m, &Named("python"), new_message("Hello!".into()).unwrap())
+            .await;
+        assert!(r.is_some());
+    }
+}
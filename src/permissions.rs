@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Client;
+use tracing::{event, Level};
+
+use crate::config::{AccountConfig, Permission};
+use crate::handlers::{Handler, Message};
+use crate::middleware::{Middleware, Verdict};
+
+/// Decides who may use each handler, and commands of a handler that need
+/// more than the handler itself, such as `quote.delete`.
+#[derive(Debug)]
+pub struct Permissions {
+    client: Client,
+    account: Arc<AccountConfig>,
+}
+
+impl Permissions {
+    pub(crate) fn new(client: Client, account: Arc<AccountConfig>) -> Self {
+        Self { client, account }
+    }
+
+    /// Returns what the account requires for `name`, a handler or one of
+    /// its commands, or `default` if it doesn't say.
+    fn required(&self, name: &str, default: Permission) -> Permission {
+        self.account
+            .permissions
+            .get(name)
+            .cloned()
+            .unwrap_or(default)
+    }
+
+    fn is_admin(&self, user: &UserId) -> bool {
+        self.account.admins.iter().any(|a| a == user.as_str())
+    }
+
+    /// Returns whether `user` may use `handler` in `room`.
+    pub(crate) async fn allows(&self, handler: &dyn Handler, room: &Joined, user: &UserId) -> bool {
+        self.may(handler.name(), handler.permission(), room, user)
+            .await
+    }
+
+    /// Returns whether `user` may use `name`, a handler or one of its
+    /// commands, in `room`. `default` is what it requires unless the account
+    /// configures otherwise.
+    pub(crate) async fn may(
+        &self,
+        name: &str,
+        default: Permission,
+        room: &Joined,
+        user: &UserId,
+    ) -> bool {
+        let required = self.required(name, default);
+        let power_level = match required {
+            Permission::PowerLevel(_) => match room.get_member(user).await {
+                Ok(member) => member.map(|m| m.power_level()),
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "failed to look up {} in {}: {}",
+                        user,
+                        room.room_id(),
+                        e
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
+        self.allows_with(name, required, user, power_level)
+    }
+
+    /// Returns whether `user`, who has `power_level` in the room if they're
+    /// a member, meets `required` for `name`.
+    fn allows_with(
+        &self,
+        name: &str,
+        required: Permission,
+        user: &UserId,
+        power_level: Option<i64>,
+    ) -> bool {
+        if self.is_admin(user) {
+            return true;
+        }
+
+        match required {
+            Permission::Anyone => true,
+            Permission::Admin => false,
+            Permission::PowerLevel(level) => matches!(power_level, Some(p) if p >= level),
+            Permission::Group(group) => match self.account.groups.get(&group) {
+                Some(members) => members.iter().any(|m| m == user.as_str()),
+                None => {
+                    event!(Level::WARN, "{} requires unknown group {}", name, group);
+                    false
+                }
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Permissions {
+    fn name(&self) -> &str {
+        "permissions"
+    }
+
+    async fn allow(&self, message: &Message, handler: &dyn Handler) -> Verdict {
+        let room = match self.client.get_joined_room(&message.room_id) {
+            Some(r) => r,
+            None => return Verdict::Deny(None),
+        };
+        if self.allows(handler, &room, &message.sender).await {
+            return Verdict::Allow;
+        }
+
+        event!(
+            Level::INFO,
+            "{} may not use {}",
+            message.sender,
+            handler.name()
+        );
+        // only explicit commands are refused out loud; ordinary chat that a
+        // handler happens to match shouldn't get a telling-off
+        let body = message.body.trim_start();
+        let command = body.starts_with('!') || body.starts_with("* !");
+        Verdict::Deny(command.then(|| refusal(&message.sender_name)))
+    }
+}
+
+/// What to tell someone who isn't allowed to do what they asked.
+pub(crate) fn refusal(sender_name: &str) -> String {
+    format!("Sorry, {}, you're not allowed to do that.", sender_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use url::Url;
+
+    use super::*;
+
+    fn permissions() -> Permissions {
+        let account = serde_json::from_value(serde_json::json!({
+            "homeserver": "https://example.org",
+            "admins": ["@admin:example.org"],
+            "groups": { "ops": ["@op:example.org"] },
+            "permissions": { "relaxed": "anyone", "quote.delete": { "group": "ops" } },
+        }))
+        .unwrap();
+        let client = Client::new(Url::parse("https://example.org").unwrap()).unwrap();
+        Permissions::new(client, Arc::new(account))
+    }
+
+    fn user(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    /// Whether `user` with `power_level` may use `name`, which requires
+    /// `default` unless the account says otherwise.
+    fn may(
+        p: &Permissions,
+        name: &str,
+        default: Permission,
+        user: &UserId,
+        power_level: Option<i64>,
+    ) -> bool {
+        p.allows_with(name, p.required(name, default), user, power_level)
+    }
+
+    #[test]
+    fn checks_each_kind_of_permission() {
+        let p = permissions();
+        let someone = user("@someone:example.org");
+
+        assert!(may(&p, "h", Permission::Anyone, &someone, None));
+        assert!(!may(&p, "h", Permission::Admin, &someone, None));
+
+        let mods = Permission::PowerLevel(50);
+        assert!(may(&p, "h", mods.clone(), &someone, Some(50)));
+        assert!(!may(&p, "h", mods.clone(), &someone, Some(49)));
+        assert!(!may(&p, "h", mods, &someone, None));
+
+        let ops = Permission::Group("ops".into());
+        assert!(may(&p, "h", ops.clone(), &user("@op:example.org"), None));
+        assert!(!may(&p, "h", ops, &someone, None));
+        let unknown = Permission::Group("nobody".into());
+        assert!(!may(&p, "h", unknown, &user("@op:example.org"), None));
+    }
+
+    #[test]
+    fn admins_may_use_anything() {
+        let p = permissions();
+        let admin = user("@admin:example.org");
+        assert!(may(&p, "h", Permission::Admin, &admin, None));
+        assert!(may(&p, "h", Permission::PowerLevel(100), &admin, None));
+        assert!(may(
+            &p,
+            "quote.delete",
+            Permission::PowerLevel(50),
+            &admin,
+            None
+        ));
+    }
+
+    #[test]
+    fn the_account_overrides_handlers_and_commands() {
+        let p = permissions();
+        let someone = user("@someone:example.org");
+        assert!(may(&p, "relaxed", Permission::Admin, &someone, None));
+
+        // quote.delete is for ops, not moderators, in this account
+        let mods = Permission::PowerLevel(50);
+        assert!(!may(&p, "quote.delete", mods.clone(), &someone, Some(100)));
+        assert!(may(
+            &p,
+            "quote.delete",
+            mods,
+            &user("@op:example.org"),
+            None
+        ));
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
+
+use crate::handlers::Message;
+
+/// How many responses to remember.
+const CAPACITY: usize = 512;
+
+/// A message the bot sent in answer to another.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    /// The name of the handler that sent it.
+    pub handler: String,
+    /// The message it answered.
+    pub trigger: Message,
+}
+
+/// Remembers the bot's recent responses, so that later events, such as
+/// reactions, can be traced back to the handler and message behind them.
+#[derive(Debug, Default)]
+pub struct ResponseLog {
+    responses: Mutex<VecDeque<Response>>,
+}
+
+impl ResponseLog {
+    pub(crate) fn record(&self, response: Response) {
+        let mut responses = self.responses.lock().unwrap();
+        if responses.len() >= CAPACITY {
+            responses.pop_front();
+        }
+        responses.push_back(response);
+    }
+
+    /// Returns the most recent response to `sender` in the room.
+    pub(crate) fn last_for(&self, room_id: &RoomId, sender: &UserId) -> Option<Response> {
+        self.responses
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|r| &r.room_id == room_id && &r.trigger.sender == sender)
+            .cloned()
+    }
+
+    /// Forgets a response, once it has been redacted.
+    pub(crate) fn remove(&self, room_id: &RoomId, event_id: &EventId) {
+        self.responses
+            .lock()
+            .unwrap()
+            .retain(|r| !(&r.room_id == room_id && &r.event_id == event_id));
+    }
+
+    /// Returns the response with the given event ID, if it's remembered.
+    pub(crate) fn get(&self, room_id: &RoomId, event_id: &EventId) -> Option<Response> {
+        self.responses
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|r| &r.room_id == room_id && &r.event_id == event_id)
+            .cloned()
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use matrix_sdk::ruma::{RoomId, UserId};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{sleep, Duration};
+use tracing::{event, Level};
+
+use crate::errors::*;
+use crate::handlers::new_message;
+use crate::store;
+
+/// Custom store key holding the scheduled jobs.
+const JOBS_KEY: &[u8] = b"bingo.scheduler.jobs";
+
+/// The longest the scheduler sleeps before checking for due jobs again, in
+/// seconds, so that it keeps up if the clock changes.
+const MAX_WAIT: i64 = 3600;
+
+/// How long to wait before trying again to post a one-off job that wasn't
+/// posted, in seconds.
+const RETRY_DELAY: i64 = 300;
+
+/// When a job runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    /// Once, at a time in seconds since the Unix epoch.
+    Once(i64),
+    /// Whenever a cron expression matches the time in a timezone.
+    Cron {
+        expression: String,
+        timezone: String,
+    },
+}
+
+/// A message to post to a room on a schedule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub room_id: RoomId,
+    /// The name of the handler that scheduled the job.
+    pub handler: String,
+    /// The user the job was scheduled for, if any.
+    #[serde(default)]
+    pub owner: Option<UserId>,
+    pub schedule: Schedule,
+    /// The message to post, as markdown.
+    pub message: String,
+    /// When the job last ran, or was scheduled if it hasn't yet, in seconds
+    /// since the Unix epoch.
+    pub last_run: i64,
+    /// When to try again to post a one-off job that wasn't posted, in
+    /// seconds since the Unix epoch.
+    #[serde(default)]
+    pub retry_at: Option<i64>,
+}
+
+impl Job {
+    /// Returns when the job next runs, or `None` if its schedule is no
+    /// longer valid.
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        match &self.schedule {
+            Schedule::Once(at) => {
+                let at = (*at).max(self.retry_at.unwrap_or(i64::MIN));
+                Utc.timestamp_opt(at, 0).single()
+            }
+            Schedule::Cron {
+                expression,
+                timezone,
+            } => {
+                let schedule = parse_cron(expression).ok()?;
+                let tz = parse_timezone(timezone).ok()?;
+                let since = Utc
+                    .timestamp_opt(self.last_run, 0)
+                    .single()?
+                    .with_timezone(&tz);
+                let next = schedule.after(&since).next()?;
+                Some(next.with_timezone(&Utc))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Jobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+/// Jobs that post to rooms at set times, kept in the state store so that
+/// they survive restarts. Jobs due while the bot wasn't running are run
+/// once it starts.
+#[derive(Debug)]
+pub struct Scheduler {
+    client: Client,
+    /// The timezone for schedules that don't name their own.
+    timezone: Tz,
+    lock: Mutex<()>,
+    /// Wakes the scheduler when jobs change.
+    wake: Notify,
+}
+
+impl Scheduler {
+    pub(crate) fn new(client: Client, timezone: Tz) -> Self {
+        Self {
+            client,
+            timezone,
+            lock: Mutex::new(()),
+            wake: Notify::new(),
+        }
+    }
+
+    /// The account's timezone, for schedules that don't name their own.
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// Returns every scheduled job, by ID.
+    pub async fn jobs(&self) -> Result<BTreeMap<u64, Job>> {
+        Ok(self.load().await?.jobs)
+    }
+
+    /// Posts `message` to `room_id` once, at `at`, on behalf of `owner`.
+    /// Returns the job's ID.
+    pub async fn once(
+        &self,
+        room_id: &RoomId,
+        handler: &str,
+        owner: Option<&UserId>,
+        at: DateTime<Utc>,
+        message: &str,
+    ) -> Result<u64> {
+        let schedule = Schedule::Once(at.timestamp());
+        self.add(room_id, handler, owner, schedule, message).await
+    }
+
+    /// Posts `message` to `room_id` whenever the cron `expression` matches
+    /// the time in `timezone`, or in the account's timezone if that's
+    /// `None`. Expressions have either five fields, starting with minutes,
+    /// or six or seven, starting with seconds. Returns the job's ID.
+    pub async fn cron(
+        &self,
+        room_id: &RoomId,
+        handler: &str,
+        owner: Option<&UserId>,
+        expression: &str,
+        timezone: Option<&str>,
+        message: &str,
+    ) -> Result<u64> {
+        parse_cron(expression)?;
+        let timezone = match timezone {
+            Some(tz) => parse_timezone(tz)?,
+            None => self.timezone,
+        };
+        let schedule = Schedule::Cron {
+            expression: expression.into(),
+            timezone: timezone.name().into(),
+        };
+        self.add(room_id, handler, owner, schedule, message).await
+    }
+
+    async fn add(
+        &self,
+        room_id: &RoomId,
+        handler: &str,
+        owner: Option<&UserId>,
+        schedule: Schedule,
+        message: &str,
+    ) -> Result<u64> {
+        let _guard = self.lock.lock().await;
+        let mut jobs = self.load().await?;
+        let id = jobs.next_id + 1;
+        jobs.next_id = id;
+        jobs.jobs.insert(
+            id,
+            Job {
+                room_id: room_id.clone(),
+                handler: handler.into(),
+                owner: owner.cloned(),
+                schedule,
+                message: message.into(),
+                last_run: Utc::now().timestamp(),
+                retry_at: None,
+            },
+        );
+        self.save(&jobs).await?;
+        self.wake.notify_one();
+
+        event!(Level::INFO, "scheduled job {} in room {}", id, room_id);
+        Ok(id)
+    }
+
+    /// Cancels a job. Returns false if there's no job with that ID.
+    pub async fn cancel(&self, id: u64) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let mut jobs = self.load().await?;
+        let found = jobs.jobs.remove(&id).is_some();
+        if found {
+            self.save(&jobs).await?;
+            self.wake.notify_one();
+            event!(Level::INFO, "cancelled job {}", id);
+        }
+        Ok(found)
+    }
+
+    /// Moves every job in room `from` to room `to`, such as when `from` is
+    /// upgraded. Returns how many jobs moved.
+    pub(crate) async fn move_room(&self, from: &RoomId, to: &RoomId) -> Result<usize> {
+        let _guard = self.lock.lock().await;
+        let mut jobs = self.load().await?;
+        let mut moved = 0;
+        for job in jobs.jobs.values_mut().filter(|j| &j.room_id == from) {
+            job.room_id = to.clone();
+            moved += 1;
+        }
+        if moved > 0 {
+            self.save(&jobs).await?;
+            event!(Level::INFO, "moved {} jobs from {} to {}", moved, from, to);
+        }
+        Ok(moved)
+    }
+
+    /// Runs jobs as they come due. This only returns if the state store
+    /// fails.
+    pub(crate) async fn run(self: Arc<Self>) -> Result<()> {
+        loop {
+            let next = self.run_due().await?;
+            let wait = next
+                .map(|t| (t - Utc::now()).num_seconds())
+                .unwrap_or(MAX_WAIT)
+                .clamp(1, MAX_WAIT);
+            tokio::select! {
+                _ = sleep(Duration::from_secs(wait as u64)) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+
+    /// Runs every job that's due, and returns when the next one is.
+    async fn run_due(&self) -> Result<Option<DateTime<Utc>>> {
+        let now = Utc::now();
+        let due: Vec<(u64, Job)> = self
+            .jobs()
+            .await?
+            .into_iter()
+            .filter(|(_, job)| matches!(job.next_run(), Some(t) if t <= now))
+            .collect();
+
+        let mut posted = Vec::with_capacity(due.len());
+        for (id, job) in &due {
+            posted.push(self.post(*id, job).await);
+        }
+
+        let _guard = self.lock.lock().await;
+        let mut jobs = self.load().await?;
+        for ((id, _), posted) in due.into_iter().zip(posted) {
+            let job = match jobs.jobs.get_mut(&id) {
+                Some(j) => j,
+                // cancelled while it was running
+                None => continue,
+            };
+            match job.schedule {
+                Schedule::Once(_) if posted => {
+                    jobs.jobs.remove(&id);
+                }
+                Schedule::Once(_) => {
+                    event!(
+                        Level::WARN,
+                        "job {} wasn't posted, trying again in {} seconds",
+                        id,
+                        RETRY_DELAY
+                    );
+                    job.retry_at = Some(now.timestamp() + RETRY_DELAY);
+                }
+                Schedule::Cron { .. } => job.last_run = now.timestamp(),
+            }
+        }
+
+        let invalid: Vec<u64> = jobs
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.next_run().is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in invalid {
+            event!(Level::WARN, "dropping job {}, which will never run", id);
+            jobs.jobs.remove(&id);
+        }
+        self.save(&jobs).await?;
+
+        Ok(jobs.jobs.values().filter_map(Job::next_run).min())
+    }
+
+    /// Posts a job's message, returning whether it was posted.
+    async fn post(&self, id: u64, job: &Job) -> bool {
+        let room = match self.client.get_joined_room(&job.room_id) {
+            Some(r) => r,
+            None => {
+                event!(
+                    Level::WARN,
+                    "not running job {}, since I'm not in room {}",
+                    id,
+                    job.room_id
+                );
+                return false;
+            }
+        };
+
+        let content = match new_message(job.message.clone()) {
+            Some(c) => c,
+            None => return true,
+        };
+        match room.send(content, None).await {
+            Ok(_) => {
+                event!(Level::DEBUG, "ran job {} in room {}", id, job.room_id);
+                true
+            }
+            Err(e) => {
+                event!(Level::WARN, "job {} failed to post: {}", id, e);
+                false
+            }
+        }
+    }
+
+    async fn load(&self) -> Result<Jobs> {
+        store::load(&self.client, JOBS_KEY).await
+    }
+
+    async fn save(&self, jobs: &Jobs) -> Result<()> {
+        store::save(&self.client, JOBS_KEY, jobs).await
+    }
+}
+
+/// Parses a cron expression, allowing the five-field form without seconds.
+/// The five-field form follows crontab, with days of the week numbered from
+/// 0 or 7 for Sunday; the longer forms number them from 1 for Sunday.
+fn parse_cron(expression: &str) -> Result<cron::Schedule> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let expression = match &fields[..] {
+        [minute, hour, day, month, weekday] => format!(
+            "0 {} {} {} {} {}",
+            minute,
+            hour,
+            day,
+            month,
+            crontab_weekdays(weekday)?
+        ),
+        _ => expression.to_string(),
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|e| Error::BotError(format!("invalid cron expression: {}", e)))
+}
+
+/// Renumbers a crontab day-of-week field, where Sunday is 0 or 7, for the
+/// cron crate, where it's 1. Numbered ranges and steps are expanded into
+/// lists of days; names, `*` and `?` are left as they are.
+fn crontab_weekdays(field: &str) -> Result<String> {
+    let invalid = || Error::BotError(format!("invalid day of the week \"{}\"", field));
+    let mut items = Vec::new();
+    for item in field.split(',') {
+        if item.starts_with(|c: char| c.is_ascii_alphabetic()) || item == "?" || item == "*" {
+            items.push(item.to_string());
+            continue;
+        }
+
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| invalid())?),
+            None => (item, 1),
+        };
+        let (first, last): (u32, u32) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((first, last)) => (
+                first.parse().map_err(|_| invalid())?,
+                last.parse().map_err(|_| invalid())?,
+            ),
+            None => {
+                let day = range.parse().map_err(|_| invalid())?;
+                (day, if step > 1 { 6 } else { day })
+            }
+        };
+        if step == 0 || first > last || last > 7 {
+            return Err(invalid());
+        }
+        for day in (first..=last).step_by(step) {
+            let day = (day % 7 + 1).to_string();
+            if !items.contains(&day) {
+                items.push(day);
+            }
+        }
+    }
+    Ok(items.join(","))
+}
+
+/// Parses a timezone name from the tz database, such as "Europe/London".
+pub(crate) fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse()
+        .map_err(|_| Error::BotError(format!("unknown timezone \"{}\"", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn job(schedule: Schedule, last_run: i64) -> Job {
+        Job {
+            room_id: RoomId::try_from("!room:example.org").unwrap(),
+            handler: "test".into(),
+            owner: None,
+            schedule,
+            message: "hello".into(),
+            last_run,
+            retry_at: None,
+        }
+    }
+
+    fn cron(expression: &str, timezone: &str) -> Schedule {
+        Schedule::Cron {
+            expression: expression.into(),
+            timezone: timezone.into(),
+        }
+    }
+
+    #[test]
+    fn once_runs_at_its_time() {
+        let j = job(Schedule::Once(1_700_000_000), 0);
+        assert_eq!(j.next_run().unwrap().timestamp(), 1_700_000_000);
+    }
+
+    #[test]
+    fn cron_runs_in_its_timezone() {
+        // 2021-06-01 00:00 UTC
+        let since = 1_622_505_600;
+
+        let utc = job(cron("0 9 * * *", "UTC"), since);
+        assert_eq!(
+            utc.next_run().unwrap(),
+            Utc.ymd(2021, 6, 1).and_hms(9, 0, 0)
+        );
+
+        // London is on summer time, an hour ahead of UTC
+        let london = job(cron("0 9 * * *", "Europe/London"), since);
+        assert_eq!(
+            london.next_run().unwrap(),
+            Utc.ymd(2021, 6, 1).and_hms(8, 0, 0)
+        );
+    }
+
+    #[test]
+    fn cron_accepts_seconds() {
+        let j = job(cron("30 0 9 * * *", "UTC"), 1_622_505_600);
+        assert_eq!(j.next_run().unwrap(), Utc.ymd(2021, 6, 1).and_hms(9, 0, 30));
+    }
+
+    #[test]
+    fn cron_numbers_weekdays_like_crontab() {
+        // 2021-06-05 00:00 UTC, a Saturday
+        let since = 1_622_851_200;
+
+        let weekdays = job(cron("0 9 * * 1-5", "UTC"), since);
+        assert_eq!(
+            weekdays.next_run().unwrap(),
+            Utc.ymd(2021, 6, 7).and_hms(9, 0, 0)
+        );
+        for sunday in &["0 9 * * 0", "0 9 * * 7", "0 9 * * SUN"] {
+            let j = job(cron(sunday, "UTC"), since);
+            assert_eq!(j.next_run().unwrap(), Utc.ymd(2021, 6, 6).and_hms(9, 0, 0));
+        }
+
+        assert_eq!(crontab_weekdays("1-5").unwrap(), "2,3,4,5,6");
+        assert_eq!(crontab_weekdays("5-7").unwrap(), "6,7,1");
+        assert_eq!(crontab_weekdays("*/2").unwrap(), "1,3,5,7");
+        assert_eq!(crontab_weekdays("0,6").unwrap(), "1,7");
+        assert_eq!(crontab_weekdays("MON-FRI").unwrap(), "MON-FRI");
+        assert!(crontab_weekdays("8").is_err());
+        assert!(crontab_weekdays("5-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_one_off_jobs_until_posted() {
+        let client = Client::new(url::Url::parse("https://example.org").unwrap()).unwrap();
+        let scheduler = Scheduler::new(client, chrono_tz::UTC);
+        let room = RoomId::try_from("!room:example.org").unwrap();
+        let at = Utc::now() - chrono::Duration::minutes(1);
+        let id = scheduler
+            .once(&room, "test", None, at, "hello")
+            .await
+            .unwrap();
+
+        // the bot isn't in the room, so the job can't be posted yet
+        let next = scheduler.run_due().await.unwrap().unwrap();
+        let jobs = scheduler.jobs().await.unwrap();
+        assert_eq!(jobs[&id].next_run(), Some(next));
+        assert!(next > Utc::now() + chrono::Duration::seconds(RETRY_DELAY - 60));
+    }
+
+    #[test]
+    fn invalid_schedules_never_run() {
+        assert!(job(Schedule::Once(i64::MAX), 0).next_run().is_none());
+        assert!(job(cron("0 9 * * *", "UTC"), i64::MIN).next_run().is_none());
+        assert!(job(cron("not cron", "UTC"), 0).next_run().is_none());
+        assert!(job(cron("0 9 * * *", "Mars/Olympus"), 0)
+            .next_run()
+            .is_none());
+        assert!(parse_cron("0 9 * *").is_err());
+        assert!(parse_timezone("Mars/Olympus").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use matrix_sdk::ruma::RoomId;
+
+use crate::config::{AccountConfig, RoomConfig};
+use crate::spaces::Spaces;
+use crate::upgrades::RoomUpgrades;
+
+/// Works out which configured settings apply to a room. A room's own
+/// settings, or those of a room it replaced, come first; failing that, the
+/// settings of the space it belongs to.
+#[derive(Debug)]
+pub(crate) struct RoomSettings {
+    account: Arc<AccountConfig>,
+    upgrades: Arc<RoomUpgrades>,
+    spaces: Arc<Spaces>,
+}
+
+impl RoomSettings {
+    pub(crate) fn new(
+        account: Arc<AccountConfig>,
+        upgrades: Arc<RoomUpgrades>,
+        spaces: Arc<Spaces>,
+    ) -> Self {
+        Self {
+            account,
+            upgrades,
+            spaces,
+        }
+    }
+
+    pub(crate) fn get(&self, room_id: &RoomId) -> Option<&RoomConfig> {
+        let config_id = self.upgrades.config_id(&self.account, room_id);
+        if let Some(config) = self.account.rooms.get(config_id.as_str()) {
+            return Some(config);
+        }
+
+        let space = self
+            .spaces
+            .space_of(room_id)
+            .or_else(|| self.spaces.space_of(&config_id))?;
+        self.account.spaces.get(space.as_str())
+    }
+
+    /// Returns the display name the bot should use in the room.
+    pub(crate) fn display_name(&self, room_id: &RoomId) -> &str {
+        self.get(room_id)
+            .and_then(|r| r.display_name.as_deref())
+            .unwrap_or_else(|| self.account.display_name())
+    }
+
+    /// Returns whether the named handler may respond in the room.
+    pub(crate) fn handler_enabled(&self, room_id: &RoomId, name: &str) -> bool {
+        match self.get(room_id).and_then(|r| r.handlers.as_ref()) {
+            Some(h) => h.iter().any(|n| n == name),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tracing::{event, Level};
+
+/// Tells every part of the bot when it's time to stop. Clones share the same
+/// state, so one can be handed to each account.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    /// Triggers shutdown when the process receives SIGINT or SIGTERM.
+    pub fn on_signals(&self) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interrupt = signal(SignalKind::interrupt()).expect("can't handle SIGINT");
+            let mut terminate = signal(SignalKind::terminate()).expect("can't handle SIGTERM");
+            tokio::select! {
+                _ = interrupt.recv() => event!(Level::INFO, "received SIGINT"),
+                _ = terminate.recv() => event!(Level::INFO, "received SIGTERM"),
+            }
+            this.trigger();
+        })
+    }
+
+    pub fn trigger(&self) {
+        // there's always a receiver, since we hold one
+        let _ = self.tx.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Waits until shutdown is triggered.
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A token held by work in progress, such as a handler's response. The
+/// work is finished once it's dropped.
+pub(crate) type Task = mpsc::Sender<()>;
+
+/// Keeps track of work in progress, so that shutdown can wait for it.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    tx: Mutex<Option<mpsc::Sender<()>>>,
+    rx: tokio::sync::Mutex<mpsc::Receiver<()>>,
+}
+
+impl InFlight {
+    pub(crate) fn new() -> Self {
+        let (tx, rx) = mpsc::channel(1);
+        Self {
+            tx: Mutex::new(Some(tx)),
+            rx: tokio::sync::Mutex::new(rx),
+        }
+    }
+
+    /// Starts tracking a piece of work, unless shutdown has begun.
+    pub(crate) fn start(&self) -> Option<Task> {
+        self.tx.lock().unwrap().clone()
+    }
+
+    /// Stops new work from starting and waits up to `limit` for the work in
+    /// progress to finish. Returns whether it did.
+    pub(crate) async fn drain(&self, limit: Duration) -> bool {
+        self.tx.lock().unwrap().take();
+        let mut rx = self.rx.lock().await;
+        // nothing is ever sent, so this returns once every task is dropped
+        timeout(limit, rx.recv()).await.is_ok()
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
+
+use matrix_sdk::ruma::api::client::r0::state::get_state_events;
+use matrix_sdk::ruma::events::AnyStateEvent;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use tracing::{event, Level};
+
+use crate::config::AccountConfig;
+use crate::errors::*;
+use crate::joins::JoinQueue;
+
+/// Keeps the bot in every room of the spaces it's configured for, and
+/// remembers which space each of those rooms belongs to.
+#[derive(Debug)]
+pub(crate) struct Spaces {
+    client: Client,
+    account: Arc<AccountConfig>,
+    /// Maps each room to the configured space that contains it.
+    parents: RwLock<HashMap<RoomId, RoomId>>,
+}
+
+impl Spaces {
+    pub(crate) fn new(client: Client, account: Arc<AccountConfig>) -> Self {
+        Self {
+            client,
+            account,
+            parents: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the configured space that contains `room_id`, if any.
+    pub(crate) fn space_of(&self, room_id: &RoomId) -> Option<RoomId> {
+        self.parents.read().unwrap().get(room_id).cloned()
+    }
+
+    pub(crate) fn is_configured(&self, room_id: &RoomId) -> bool {
+        self.account.spaces.contains_key(room_id.as_str())
+    }
+
+    /// Joins each configured space and every room in it.
+    pub(crate) async fn join_all(&self, joins: &JoinQueue) {
+        for space in self.account.spaces.keys() {
+            let space_id = match RoomId::try_from(space.as_str()) {
+                Ok(id) => id,
+                Err(e) => {
+                    event!(Level::WARN, "invalid space ID {}: {}", space, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.join_space(&space_id, joins).await {
+                event!(Level::WARN, "failed to join space {}: {}", space_id, e);
+            }
+        }
+    }
+
+    async fn join_space(&self, space_id: &RoomId, joins: &JoinQueue) -> Result<()> {
+        if self.client.get_joined_room(space_id).is_none() {
+            self.client.join_room_by_id(space_id).await?;
+            event!(Level::INFO, "joined space {}", space_id);
+        }
+
+        let state = self
+            .client
+            .send(get_state_events::Request::new(space_id), None)
+            .await?;
+        for ev in state.room_state {
+            if let Ok(AnyStateEvent::SpaceChild(child)) = ev.deserialize() {
+                let child_id = match RoomId::try_from(child.state_key.as_str()) {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                };
+                if matches!(&child.content.via, Some(v) if !v.is_empty()) {
+                    self.add_child(space_id, &child_id, joins).await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records that `child` belongs to `space` and joins it.
+    pub(crate) async fn add_child(&self, space: &RoomId, child: &RoomId, joins: &JoinQueue) {
+        self.parents
+            .write()
+            .unwrap()
+            .insert(child.clone(), space.clone());
+
+        if self.client.get_joined_room(child).is_some() {
+            return;
+        }
+        match self.client.join_room_by_id(child).await {
+            Ok(_) => event!(Level::INFO, "joined room {} in space {}", child, space),
+            Err(e) => joins.add(child, &e.into()).await,
+        }
+    }
+
+    /// Forgets that `child` belongs to `space`. The bot stays in the room.
+    pub(crate) fn remove_child(&self, space: &RoomId, child: &RoomId) {
+        let mut parents = self.parents.write().unwrap();
+        if parents.get(child) == Some(space) {
+            parents.remove(child);
+            event!(
+                Level::INFO,
+                "room {} was removed from space {}",
+                child,
+                space
+            );
+        }
+    }
+}
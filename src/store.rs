@@ -0,0 +1,94 @@
+//! Helpers for keeping the bot's own data in the state store, alongside the
+//! SDK's.
+
+use std::collections::BTreeSet;
+
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::*;
+
+/// Reads the value stored under `key`, or the default if there isn't one.
+pub(crate) async fn load<T: DeserializeOwned + Default>(client: &Client, key: &[u8]) -> Result<T> {
+    match client.store().get_custom_value(key).await? {
+        Some(v) => Ok(serde_json::from_slice(&v)?),
+        None => Ok(T::default()),
+    }
+}
+
+/// Stores `value` under `key`.
+pub(crate) async fn save<T: Serialize>(client: &Client, key: &[u8], value: &T) -> Result<()> {
+    client
+        .store()
+        .set_custom_value(key, serde_json::to_vec(value)?)
+        .await?;
+    Ok(())
+}
+
+/// Prefix of the keys holding data about a single room. Each room also has
+/// an index of the names stored for it, so that they can be found again
+/// when the room is upgraded.
+const ROOM_PREFIX: &str = "bingo.room.";
+
+fn room_key(room_id: &RoomId, name: &str) -> String {
+    format!("{}{}.{}", ROOM_PREFIX, room_id, name)
+}
+
+fn room_index_key(room_id: &RoomId) -> String {
+    format!("{}{}", ROOM_PREFIX, room_id)
+}
+
+/// Reads the value stored as `name` for `room_id`, or the default if there
+/// isn't one.
+pub(crate) async fn load_room<T: DeserializeOwned + Default>(
+    client: &Client,
+    room_id: &RoomId,
+    name: &str,
+) -> Result<T> {
+    load(client, room_key(room_id, name).as_bytes()).await
+}
+
+/// Stores `value` as `name` for `room_id`, so that it moves to the room's
+/// replacement if the room is upgraded.
+pub(crate) async fn save_room<T: Serialize>(
+    client: &Client,
+    room_id: &RoomId,
+    name: &str,
+    value: &T,
+) -> Result<()> {
+    save(client, room_key(room_id, name).as_bytes(), value).await?;
+
+    let index_key = room_index_key(room_id);
+    let mut names: BTreeSet<String> = load(client, index_key.as_bytes()).await?;
+    if names.insert(name.into()) {
+        save(client, index_key.as_bytes(), &names).await?;
+    }
+    Ok(())
+}
+
+/// Copies everything stored for room `from` with `save_room` to room `to`,
+/// without overwriting anything `to` already has. State kept under keys of
+/// its own, such as the scheduler's jobs, has to be moved separately;
+/// feedback and the ignore list aren't kept by room, so they stay as they
+/// are.
+pub(crate) async fn migrate_room(client: &Client, from: &RoomId, to: &RoomId) -> Result<()> {
+    let names: BTreeSet<String> = load(client, room_index_key(from).as_bytes()).await?;
+    let store = client.store();
+    for name in names {
+        let (old, new) = (room_key(from, &name), room_key(to, &name));
+        if store.get_custom_value(new.as_bytes()).await?.is_some() {
+            continue;
+        }
+        if let Some(value) = store.get_custom_value(old.as_bytes()).await? {
+            store.set_custom_value(new.as_bytes(), value).await?;
+
+            let index_key = room_index_key(to);
+            let mut to_names: BTreeSet<String> = load(client, index_key.as_bytes()).await?;
+            to_names.insert(name);
+            save(client, index_key.as_bytes(), &to_names).await?;
+        }
+    }
+    Ok(())
+}
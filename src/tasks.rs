@@ -0,0 +1,104 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+use tracing::{event, Instrument, Level};
+
+use crate::errors::*;
+
+/// The longest a failed task waits before being restarted.
+const MAX_RESTART_DELAY: u64 = 300;
+
+/// Background tasks owned by the bot. Tasks are restarted when they fail or
+/// panic, and cancelled when the bot stops. Clones share the same tasks.
+#[derive(Debug, Clone)]
+pub struct Tasks {
+    /// The running tasks, or `None` once they've been stopped.
+    handles: Arc<Mutex<Option<Vec<JoinHandle<()>>>>>,
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Self {
+            handles: Arc::new(Mutex::new(Some(Vec::new()))),
+        }
+    }
+
+    /// Runs the future `task` returns in the background, calling `task`
+    /// again for a fresh one whenever it fails or panics. A task that
+    /// returns `Ok` isn't restarted. Returns `false` if the bot is already
+    /// stopping, in which case nothing is spawned.
+    pub fn spawn<F, Fut>(&self, name: &str, task: F) -> bool
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let mut handles = self.handles.lock().unwrap();
+        let handles = match handles.as_mut() {
+            Some(h) => h,
+            None => {
+                event!(Level::DEBUG, "not starting task {} while stopping", name);
+                return false;
+            }
+        };
+
+        let task_name = name.to_string();
+        let supervisor = async move {
+            let mut delay = 1;
+            loop {
+                let run = AbortOnDrop(tokio::spawn(task().in_current_span()));
+                match run.wait().await {
+                    Ok(Ok(())) => {
+                        event!(Level::DEBUG, "task {} finished", task_name);
+                        return;
+                    }
+                    Ok(Err(e)) => event!(Level::WARN, "task {} failed: {}", task_name, e),
+                    Err(e) => event!(Level::ERROR, "task {} panicked: {}", task_name, e),
+                }
+
+                event!(Level::INFO, "restarting task {} in {}s", task_name, delay);
+                sleep(Duration::from_secs(delay)).await;
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+            }
+        };
+
+        handles.push(tokio::spawn(supervisor.in_current_span()));
+        event!(Level::DEBUG, "started task {}", name);
+        true
+    }
+
+    /// Cancels every task, and keeps new ones from starting.
+    pub fn stop(&self) {
+        let handles = match self.handles.lock().unwrap().take() {
+            Some(h) => h,
+            None => return,
+        };
+        for handle in &handles {
+            handle.abort();
+        }
+        event!(Level::DEBUG, "cancelled {} background tasks", handles.len());
+    }
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Aborts a task when dropped, so that cancelling a supervisor also cancels
+/// the attempt it's waiting on.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> AbortOnDrop<T> {
+    async fn wait(mut self) -> std::result::Result<T, tokio::task::JoinError> {
+        (&mut self.0).await
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
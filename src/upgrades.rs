@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use tracing::{event, Level};
+
+use crate::config::AccountConfig;
+use crate::errors::*;
+use crate::joins::JoinQueue;
+use crate::scheduler::Scheduler;
+use crate::store;
+
+/// Custom store key mapping upgraded rooms to the rooms they replaced.
+const PREDECESSORS_KEY: &[u8] = b"bingo.rooms.predecessors";
+
+/// Follows rooms to their replacements when they're upgraded, remembering
+/// the old rooms so that settings configured for them still apply.
+#[derive(Debug)]
+pub(crate) struct RoomUpgrades {
+    client: Client,
+    predecessors: RwLock<HashMap<RoomId, RoomId>>,
+}
+
+impl RoomUpgrades {
+    pub(crate) fn new(client: Client) -> Self {
+        Self {
+            client,
+            predecessors: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the rooms followed so far from the state store.
+    pub(crate) async fn load(&self) -> Result<()> {
+        let predecessors = store::load(&self.client, PREDECESSORS_KEY).await?;
+        *self.predecessors.write().unwrap() = predecessors;
+        Ok(())
+    }
+
+    /// Returns the ID `room_id`'s settings are configured under: its own, or
+    /// that of the earliest room it replaced that has settings.
+    pub(crate) fn config_id(&self, account: &AccountConfig, room_id: &RoomId) -> RoomId {
+        let predecessors = self.predecessors.read().unwrap();
+        let mut current = room_id;
+        // guard against a cycle of upgrades
+        for _ in 0..=predecessors.len() {
+            if account.rooms.contains_key(current.as_str()) {
+                return current.clone();
+            }
+            match predecessors.get(current) {
+                Some(p) => current = p,
+                None => break,
+            }
+        }
+        room_id.clone()
+    }
+
+    /// Moves from `room` to `successor`, the room that replaced it,
+    /// bringing along everything stored for it and its scheduled jobs. If
+    /// `successor` can't be joined yet, the join is queued and `room` is left
+    /// once it succeeds.
+    pub(crate) async fn follow(
+        &self,
+        room: &Joined,
+        successor: &RoomId,
+        joins: &JoinQueue,
+        scheduler: &Scheduler,
+    ) -> Result<()> {
+        let old = room.room_id();
+        event!(Level::INFO, "room {} was upgraded to {}", old, successor);
+        // record the upgrade first, so the new room's settings are right as
+        // soon as we join it
+        self.record(old, successor, scheduler).await?;
+
+        // as when the upgrade invited us and we've already accepted
+        if self.client.get_joined_room(successor).is_some() {
+            room.leave().await?;
+            event!(
+                Level::INFO,
+                "left room {}, which was replaced by {}",
+                old,
+                successor
+            );
+            return Ok(());
+        }
+
+        match self.client.join_room_by_id(successor).await {
+            Ok(_) => {
+                room.leave().await?;
+                event!(
+                    Level::INFO,
+                    "moved from room {} to its replacement {}",
+                    old,
+                    successor
+                );
+            }
+            Err(e) => joins.add(successor, &e.into()).await,
+        }
+
+        Ok(())
+    }
+
+    /// Remembers that `successor` replaced `old`, and moves what's stored
+    /// for `old` and its scheduled jobs over to `successor`.
+    async fn record(&self, old: &RoomId, successor: &RoomId, scheduler: &Scheduler) -> Result<()> {
+        let predecessors = {
+            let mut predecessors = self.predecessors.write().unwrap();
+            predecessors.insert(successor.clone(), old.clone());
+            predecessors.clone()
+        };
+        store::save(&self.client, PREDECESSORS_KEY, &predecessors).await?;
+        store::migrate_room(&self.client, old, successor).await?;
+        scheduler.move_room(old, successor).await?;
+        Ok(())
+    }
+
+    /// Leaves the room `room_id` replaced, if the bot is still in it, as it
+    /// is when joining `room_id` had to be queued.
+    pub(crate) async fn leave_predecessor(&self, room_id: &RoomId) -> Result<()> {
+        let predecessor = match self.predecessors.read().unwrap().get(room_id) {
+            Some(p) => p.clone(),
+            None => return Ok(()),
+        };
+        if let Some(room) = self.client.get_joined_room(&predecessor) {
+            room.leave().await?;
+            event!(
+                Level::INFO,
+                "left room {}, which was replaced by {}",
+                predecessor,
+                room_id
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use chrono::{Duration, Utc};
+    use url::Url;
+
+    use super::*;
+
+    /// `follow` does this whether or not the bot had already joined the
+    /// successor.
+    #[tokio::test]
+    async fn records_upgrades() {
+        let client = Client::new(Url::parse("https://example.org").unwrap()).unwrap();
+        let upgrades = RoomUpgrades::new(client.clone());
+        let scheduler = Scheduler::new(client.clone(), chrono_tz::UTC);
+        let old = RoomId::try_from("!old:example.org").unwrap();
+        let new = RoomId::try_from("!new:example.org").unwrap();
+        let account: AccountConfig = serde_json::from_value(serde_json::json!({
+            "homeserver": "https://example.org",
+            "rooms": { "!old:example.org": {} },
+        }))
+        .unwrap();
+
+        store::save_room(&client, &old, "karma", &vec![1, 2, 3])
+            .await
+            .unwrap();
+        let at = Utc::now() + Duration::hours(1);
+        scheduler
+            .once(&old, "remind", None, at, "hello")
+            .await
+            .unwrap();
+
+        upgrades.record(&old, &new, &scheduler).await.unwrap();
+
+        assert_eq!(upgrades.config_id(&account, &new), old);
+        let karma: Vec<i32> = store::load_room(&client, &new, "karma").await.unwrap();
+        assert_eq!(karma, [1, 2, 3]);
+        let jobs = scheduler.jobs().await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs.values().all(|j| j.room_id == new));
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use matrix_sdk::room::{Joined, Room};
+use matrix_sdk::ruma::events::room::message::{
+    MessageEventContent, MessageType, TextMessageEventContent,
+};
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{event, Level};
+
+use crate::config::WelcomeConfig;
+use crate::room_name_or_id;
+use crate::settings::RoomSettings;
+
+/// The most members named in a single welcome. Any more are counted.
+const MAX_NAMES: usize = 10;
+
+/// Greeting state for a single room.
+#[derive(Debug, Default)]
+struct RoomState {
+    last_sent: Option<Instant>,
+    /// Members waiting to be greeted once the cooldown has passed.
+    waiting: Vec<String>,
+    /// How many more members are waiting, beyond those named.
+    others: usize,
+}
+
+/// Greets new members in rooms that have a welcome message configured,
+/// batching up members who join during the cooldown.
+#[derive(Debug)]
+pub(crate) struct Welcomer {
+    client: Client,
+    settings: Arc<RoomSettings>,
+    rooms: Mutex<HashMap<RoomId, RoomState>>,
+}
+
+impl Welcomer {
+    pub(crate) fn new(client: Client, settings: Arc<RoomSettings>) -> Self {
+        Self {
+            client,
+            settings,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Greets `name`, who just joined `room`, now or once the cooldown has
+    /// passed.
+    pub(crate) async fn welcome(self: &Arc<Self>, room: &Joined, name: &str) {
+        let config = match self
+            .settings
+            .get(room.room_id())
+            .and_then(|r| r.welcome.as_ref())
+        {
+            Some(c) => c.clone(),
+            None => return,
+        };
+        let cooldown = Duration::from_secs(config.cooldown);
+
+        let wait = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let state = rooms.entry(room.room_id().clone()).or_default();
+            let remaining = state
+                .last_sent
+                .map(|t| cooldown.saturating_sub(t.elapsed()))
+                .unwrap_or_default();
+
+            if remaining.is_zero() && state.waiting.is_empty() {
+                state.last_sent = Some(Instant::now());
+                None
+            } else if state.waiting.len() >= MAX_NAMES {
+                state.others += 1;
+                return;
+            } else {
+                state.waiting.push(name.to_string());
+                // only the first member to wait schedules the greeting
+                if state.waiting.len() > 1 {
+                    return;
+                }
+                Some(remaining)
+            }
+        };
+
+        match wait {
+            None => self.send(room, &config, &[name.to_string()], 0).await,
+            Some(remaining) => {
+                event!(
+                    Level::DEBUG,
+                    "holding welcome in {} for {}s",
+                    room.room_id(),
+                    remaining.as_secs()
+                );
+                let this = self.clone();
+                let room_id = room.room_id().clone();
+                tokio::spawn(async move {
+                    sleep(remaining).await;
+                    let (names, others) = {
+                        let mut rooms = this.rooms.lock().unwrap();
+                        let state = rooms.entry(room_id.clone()).or_default();
+                        state.last_sent = Some(Instant::now());
+                        let others = std::mem::take(&mut state.others);
+                        (std::mem::take(&mut state.waiting), others)
+                    };
+                    if let Some(room) = this.client.get_joined_room(&room_id) {
+                        this.send(&room, &config, &names, others).await;
+                    }
+                });
+            }
+        }
+    }
+
+    /// Sends the welcome for `names`, and `others` more who aren't named.
+    /// Names are chosen by the members themselves, so they're escaped to
+    /// keep them from adding links or formatting to the message.
+    async fn send(&self, room: &Joined, config: &WelcomeConfig, names: &[String], others: usize) {
+        let room_name = room_name_or_id(&Room::from(room.clone())).await;
+        let escaped: Vec<String> = names.iter().map(|n| escape_markdown(n)).collect();
+        let mut lines = vec![config
+            .message
+            .replace("{name}", &join_names(&escaped, others))
+            .replace("{room}", &room_name)];
+        for link in &config.links {
+            lines.push(format!("* [{}]({})", link.text, link.url));
+        }
+
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::new(
+            MessageType::Text(TextMessageEventContent::markdown(lines.join("\n"))),
+        ));
+        match room.send(content, None).await {
+            Ok(_) => event!(
+                Level::INFO,
+                "welcomed {} to \"{}\"",
+                names.join(", "),
+                room_name
+            ),
+            Err(e) => event!(
+                Level::WARN,
+                "failed to send welcome to {}: {}",
+                room.room_id(),
+                e
+            ),
+        }
+    }
+}
+
+/// Joins names into a list like "a, b and c", or "a, b and 3 others" if
+/// there are `others` more.
+fn join_names(names: &[String], others: usize) -> String {
+    match (names, others) {
+        ([], _) => String::new(),
+        (names, 1) => format!("{} and 1 other", names.join(", ")),
+        (names, n) if n > 1 => format!("{} and {} others", names.join(", "), n),
+        ([name], _) => name.clone(),
+        ([rest @ .., last], _) => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+/// Escapes anything in `text` that markdown, or the HTML it allows, would
+/// treat as formatting.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn joins_names() {
+        assert_eq!(join_names(&names(&["a"]), 0), "a");
+        assert_eq!(join_names(&names(&["a", "b", "c"]), 0), "a, b and c");
+        assert_eq!(join_names(&names(&["a", "b"]), 1), "a, b and 1 other");
+        assert_eq!(join_names(&names(&["a", "b"]), 3), "a, b and 3 others");
+    }
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(escape_markdown("Alice"), "Alice");
+        assert_eq!(
+            escape_markdown("[click](https://evil.example)"),
+            "\\[click\\]\\(https\\:\\/\\/evil\\.example\\)"
+        );
+        assert_eq!(escape_markdown("<b>hi</b>"), "\\<b\\>hi\\<\\/b\\>");
+        assert_eq!(escape_markdown("*bold* _it_"), "\\*bold\\* \\_it\\_");
+    }
+}